@@ -1,4 +1,5 @@
-use clap::Parser;
+use clap::{value_parser, Parser, ValueEnum};
+use image::Rgb;
 
 #[derive(Debug, Parser)]
 #[clap(about, version)]
@@ -12,14 +13,416 @@ pub struct Args {
     pub output: String,
 
     /// Pixelation factor. Larger values result in more pixelation
-    #[clap(short, long, default_value = "4")]
+    #[clap(short, long, default_value = "4", value_parser = value_parser!(u32).range(1..))]
     pub pixelation_factor: u32,
 
+    /// What to do with the pixels left over at the right and bottom edges when the image size
+    /// isn't a multiple of `--pixelation-factor`
+    #[clap(long, value_enum, default_value = "partial")]
+    pub edge: Edge,
+
+    /// Filter used to downscale the image, with `--pixelation-factor` or `--resize`
+    #[clap(short, long, value_enum, default_value = "nearest")]
+    pub filter: Filter,
+
     /// Number of colors to use
     #[clap(short, long, default_value = "56")]
     pub num_colors: usize,
 
+    /// Use the colors of a palette file instead of finding a palette: a GIMP `.gpl`, an Adobe
+    /// `.act`, a JASC `.pal`, a `.hex` or `.txt` list of hex colors, or a `.png` swatch strip
+    #[clap(short = 'P', long)]
+    pub palette: Option<String>,
+
+    /// Keep only the first COUNT colors of `--palette`, and let k-means find the others, up to
+    /// `--num-colors`
+    #[clap(long, value_name = "COUNT", requires = "palette")]
+    pub lock_colors: Option<usize>,
+
+    /// Save the palette to a file: a GIMP `.gpl`, an Adobe `.act`, a JASC `.pal`, a `.hex` or
+    /// `.txt` list of hex colors, a `.json` file, or a `.bin` file of little-endian BGR555 words
+    #[clap(long, value_name = "FILE")]
+    pub palette_out: Option<String>,
+
     /// Whether to include transparent pixels in the color palette
     #[clap(short, long)]
     pub transparent: bool,
+
+    /// Pixels with a lower alpha become fully transparent, and the others fully opaque
+    #[clap(long, default_value = "128")]
+    pub alpha_threshold: u8,
+
+    /// Composite the image onto this color, as a hex string, so that all pixels become opaque
+    #[clap(long, value_parser = parse_color)]
+    pub matte: Option<Rgb<u8>>,
+
+    /// Reserve color 0 of each palette for transparent pixels, as sprites require. Each palette
+    /// gets one color less
+    #[clap(short = 'R', long)]
+    pub reserve_transparent: bool,
+
+    /// Conversion mode
+    #[clap(short, long, value_enum, default_value = "free")]
+    pub mode: Mode,
+
+    /// Size of the sprites with `--mode obj`
+    #[clap(long, value_enum, default_value = "8x8")]
+    pub sprite_size: SpriteSize,
+
+    /// Number of tile rows sharing the same palettes with `--mode hicolor`
+    #[clap(long, default_value = "1", value_parser = value_parser!(u32).range(1..))]
+    pub band_rows: u32,
+
+    /// Number of colors which can be written to the palette memory during the horizontal
+    /// blanking period after each scanline with `--mode hicolor`
+    #[clap(long, default_value = "2", value_parser = value_parser!(u32).range(1..))]
+    pub palette_writes: u32,
+
+    /// How the 4 shades are chosen from the luma of the pixels with `--mode dmg`
+    #[clap(long, value_enum, default_value = "thresholds")]
+    pub shades: Shades,
+
+    /// Colors of the 4 shades with `--mode dmg`
+    #[clap(long, value_enum, default_value = "pea-green")]
+    pub preset: Preset,
+
+    /// Custom colors of the 4 shades with `--mode dmg`, as 4 comma-separated hex strings from the
+    /// darkest to the lightest, instead of a preset
+    #[clap(long, value_parser = parse_ramp)]
+    pub ramp: Option<[Rgb<u8>; 4]>,
+
+    /// Cover the tiles with the highest error with sprites, which bring 8 more palettes, within
+    /// the sprite limits. The output is the composite image, and the background and sprite layers
+    /// are also saved next to it. Requires `--mode bg`
+    #[clap(long)]
+    pub overlay: bool,
+
+    /// Resize the image to the screen size with the given strategy, instead of pixelating it at
+    /// its original size
+    #[clap(short, long, value_enum)]
+    pub resize: Option<Resize>,
+
+    /// Screen size used with `--resize`, as `<WIDTH>x<HEIGHT>`
+    #[clap(long, default_value = "160x144", value_parser = parse_size)]
+    pub size: (u32, u32),
+
+    /// Where to place the image with `--resize fit`, or which part to keep with `--resize fill`
+    #[clap(long, value_enum, default_value = "center")]
+    pub anchor: Anchor,
+
+    /// Color of the borders added by `--resize fit`, as a hex string
+    #[clap(long, default_value = "000000", value_parser = parse_color)]
+    pub letterbox: Rgb<u8>,
+
+    /// Output one pixel per logical pixel, instead of scaling the pixelated image back up to the
+    /// size of the input image
+    #[clap(short = 'N', long)]
+    pub native: bool,
+
+    /// Integer factor to upscale the result by, for display. Scales the original size of the
+    /// image, or its native size with `--native` or `--resize`
+    #[clap(short, long, default_value = "1", value_parser = value_parser!(u32).range(1..))]
+    pub scale: u32,
+
+    /// Save the output as an indexed PNG, with the palette colors in the order of the Game Boy
+    /// Color palette indices, instead of an RGBA image
+    #[clap(short = 'I', long)]
+    pub indexed: bool,
+
+    /// Export the tile data, tile map, attribute map and palettes next to the output image, in
+    /// the given format, along with the palette write schedule with `--mode hicolor`, or the tile
+    /// data, OAM entries and palettes with `--mode obj`. Requires `--mode bg`, `hicolor` or `obj`
+    #[clap(short, long, value_enum)]
+    pub export: Option<Export>,
+
+    /// Merge near-identical tiles, differing in at most this many pixels, when the exported image
+    /// needs more unique tiles than the VRAM can hold. With 64, any tiles can be merged, so the
+    /// tiles always fit
+    #[clap(long, value_parser = value_parser!(u32).range(1..=64))]
+    pub merge_threshold: Option<u32>,
+
+    /// Color quantization algorithm used to find the palette
+    #[clap(short = 'q', long, value_enum, default_value = "kmeans")]
+    pub quantizer: Quantization,
+
+    /// Color distance metric, used to cluster colors and to match them to the palette
+    #[clap(short = 'd', long, value_enum, default_value = "rgb")]
+    pub metric: Metric,
+
+    /// Color space to run k-means in. Defaults to the color space of `--metric`
+    #[clap(long, value_enum)]
+    pub space: Option<Space>,
+
+    /// Dither the image with the given error diffusion kernel or threshold map, instead of
+    /// replacing each pixel with its closest color
+    #[clap(short = 'D', long, value_enum)]
+    pub dither: Option<Dither>,
+
+    /// Process every other row from right to left when dithering
+    #[clap(long)]
+    pub serpentine: bool,
+
+    /// Fraction of the quantization error diffused when dithering, from 0.0 to 1.0
+    #[clap(long, default_value = "1.0", value_parser = parse_fraction)]
+    pub dither_strength: f32,
+
+    /// Range by which colors are offset by the threshold map with ordered dithering, in 8-bit
+    /// units
+    #[clap(long, default_value = "32")]
+    pub dither_spread: f32,
+
+    /// Seed of the k-means random number generator, or `random` for a different seed on each
+    /// run, which is printed so that its output can be reproduced
+    #[clap(long, default_value = "0", value_parser = parse_seed)]
+    pub seed: Seed,
+
+    /// Maximum number of k-means iterations
+    #[clap(long, default_value = "1")]
+    pub max_iterations: usize,
+
+    /// k-means stops when the centroids moved less than this since the previous iteration
+    #[clap(long, default_value = "5.0")]
+    pub converge: f32,
+
+    /// Number of k-means runs with different seeds, the one with the lowest error being kept
+    #[clap(long, default_value = "1", value_parser = value_parser!(u64).range(1..))]
+    pub runs: u64,
+
+    /// Snap the k-means centroids to distinct 15-bit colors after each iteration, so that no two
+    /// palette colors end up the same
+    #[clap(long)]
+    pub snap_centroids: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Mode {
+    /// Any pixel can use any color of the palette
+    Free,
+    /// Hardware-accurate background: 8x8 tiles with 4 colors each, from 8 palettes
+    #[clap(name = "bg")]
+    Background,
+    /// Hardware-accurate sprites: 8x8 or 8x16 tiles with 3 colors and transparency each, from 8
+    /// palettes, at most 40 of them and 10 per line
+    #[clap(name = "obj")]
+    Sprite,
+    /// Hardware-accurate background with new palettes for each band of tile rows, written while
+    /// the screen is drawn: 4 palettes per band, from 2 banks used in turn
+    Hicolor,
+    /// Original Game Boy: 4 shades, chosen from the luma of the pixels and shown in the colors of
+    /// a preset
+    Dmg,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Seed {
+    /// The same seed on each run, for reproducible palettes
+    Fixed(u64),
+    /// A random seed on each run
+    Random,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Shades {
+    /// Split the luma into 4 equal ranges, at 25%, 50% and 75%
+    Thresholds,
+    /// Cluster the luma of the pixels into 4 ranges with k-means, adapting them to the image
+    Kmeans,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Preset {
+    /// The pea-green screen of the original Game Boy
+    PeaGreen,
+    /// The gray screen of the Game Boy Pocket
+    Pocket,
+    /// The blue-green backlit screen of the Game Boy Light
+    Light,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SpriteSize {
+    /// 8x8 sprites
+    #[clap(name = "8x8")]
+    Small,
+    /// 8x16 sprites, made of two consecutive tiles
+    #[clap(name = "8x16")]
+    Tall,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Metric {
+    /// Euclidean distance in sRGB
+    Rgb,
+    /// Euclidean distance in sRGB, weighted by the mean red level ("redmean")
+    Redmean,
+    /// CIE76 ΔE*, i.e. Euclidean distance in CIELAB
+    Lab,
+    /// CIEDE2000 ΔE*
+    Ciede2000,
+    /// Euclidean distance in OKLab
+    Oklab,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Quantization {
+    /// k-means clustering, slow and random but usually the most accurate
+    Kmeans,
+    /// Median cut, splitting the box of colors with the highest error at its median
+    MedianCut,
+    /// Octree, merging the least used branches of a tree of colors
+    Octree,
+    /// Wu's variance minimization, cutting boxes of 15-bit colors where the variance drops most
+    Wu,
+    /// NeuQuant, training a self-organizing map on the colors
+    Neuquant,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Space {
+    /// Gamma-encoded sRGB
+    Srgb,
+    /// CIELAB
+    Lab,
+    /// OKLab
+    Oklab,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Dither {
+    /// Floyd–Steinberg
+    FloydSteinberg,
+    /// Atkinson, which diffuses only 3/4 of the error
+    Atkinson,
+    /// Jarvis, Judice and Ninke
+    JarvisJudiceNinke,
+    /// Sierra (three-row)
+    Sierra,
+    /// Ordered, with a 2x2 Bayer matrix
+    Bayer2,
+    /// Ordered, with a 4x4 Bayer matrix
+    Bayer4,
+    /// Ordered, with an 8x8 Bayer matrix
+    Bayer8,
+    /// Ordered, with a 16x16 blue noise threshold map
+    BlueNoise,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Export {
+    /// Raw binaries: `.2bpp`, `.tilemap`, `.attrmap`, `.pal` and `.schedule` in hicolor mode, or
+    /// `.2bpp`, `.oam` and `.pal` for sprites
+    Raw,
+    /// C header and source for GBDK: `.h` and `.c`
+    Gbdk,
+    /// Raw binaries and an `.asm` file including them, for RGBDS
+    Rgbds,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Resize {
+    /// Fit the whole image inside the screen, and letterbox the rest
+    Fit,
+    /// Cover the whole screen, and crop what overflows
+    Fill,
+    /// Scale each axis independently, ignoring the aspect ratio
+    Stretch,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Edge {
+    /// Crop them, so that the output image only has whole blocks
+    Crop,
+    /// Extend the image with copies of its edge pixels up to whole blocks
+    Pad,
+    /// Downscale them as smaller blocks, so that the output image keeps its size
+    Partial,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Filter {
+    /// Keep one pixel of each block, which is sharp but noisy
+    Nearest,
+    /// Average the pixels of each block, weighted by how much of them it covers
+    Area,
+    /// Linear filter
+    Triangle,
+    /// Cubic filter, which is sharper
+    CatmullRom,
+    /// Lanczos filter with a window of 3, the sharpest
+    Lanczos3,
+    /// Gaussian filter, the smoothest
+    Gaussian,
+    /// Median of each channel in each block, which removes noise but keeps edges
+    Median,
+    /// Most frequent color in each block, which keeps the colors of flat areas and pixel art
+    Mode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Anchor {
+    TopLeft,
+    Top,
+    TopRight,
+    Left,
+    Center,
+    Right,
+    BottomLeft,
+    Bottom,
+    BottomRight,
+}
+
+/// Parses a size given as `<WIDTH>x<HEIGHT>`, e.g. `160x144`.
+fn parse_size(s: &str) -> Result<(u32, u32), String> {
+    let (width, height) = s
+        .split_once(['x', 'X'])
+        .ok_or_else(|| format!("expected <WIDTH>x<HEIGHT>, got `{s}`"))?;
+    let parse = |n: &str| {
+        n.trim()
+            .parse::<u32>()
+            .map_err(|e| format!("invalid size `{s}`: {e}"))
+    };
+    Ok((parse(width)?, parse(height)?))
+}
+
+/// Parses a color given as a hex string, e.g. `#f8f8f8` or `f8f8f8`.
+pub fn parse_color(s: &str) -> Result<Rgb<u8>, String> {
+    let hex = s.trim().trim_start_matches('#');
+    if hex.len() != 6 || !hex.is_ascii() {
+        return Err(format!("expected a hex color like `#f8f8f8`, got `{s}`"));
+    }
+    let value = u32::from_str_radix(hex, 16).map_err(|e| format!("invalid color `{s}`: {e}"))?;
+    Ok(Rgb([(value >> 16) as u8, (value >> 8) as u8, value as u8]))
+}
+
+/// Parses 4 comma-separated hex colors, e.g. `0f380f,306230,8bac0f,9bbc0f`.
+fn parse_ramp(s: &str) -> Result<[Rgb<u8>; 4], String> {
+    let colors = s
+        .split(',')
+        .map(parse_color)
+        .collect::<Result<Vec<_>, _>>()?;
+    colors
+        .try_into()
+        .map_err(|colors: Vec<_>| format!("expected 4 colors, got {}", colors.len()))
+}
+
+/// Parses a k-means seed, either a number or `random`.
+fn parse_seed(s: &str) -> Result<Seed, String> {
+    match s {
+        "random" => Ok(Seed::Random),
+        _ => s
+            .parse()
+            .map(Seed::Fixed)
+            .map_err(|_| format!("expected a number or `random`, got `{s}`")),
+    }
+}
+
+/// Parses a number between 0.0 and 1.0.
+fn parse_fraction(s: &str) -> Result<f32, String> {
+    let value = s
+        .parse::<f32>()
+        .map_err(|e| format!("invalid number `{s}`: {e}"))?;
+    if !(0.0..=1.0).contains(&value) {
+        return Err(format!("expected a number between 0.0 and 1.0, got `{s}`"));
+    }
+    Ok(value)
 }
@@ -0,0 +1,473 @@
+use std::collections::{HashMap, HashSet};
+
+use image::Rgb;
+use kmeans_colors::{get_kmeans, Calculate};
+use palette::{FromColor, Lab, Oklab, Srgb};
+use rand::{
+    distributions::{Distribution, WeightedIndex},
+    rngs::StdRng,
+    Rng, SeedableRng,
+};
+
+use crate::{
+    args::{Metric, Space},
+    quantize::Quantizer,
+    vram::{from_5bit, to_15bit, to_5bit},
+};
+
+/// Parameters of the k-means clustering.
+#[derive(Debug, Clone, Copy)]
+pub struct KmeansOptions {
+    /// The color space to cluster the pixels in, with the Euclidean distance. The centroids are
+    /// converted back to sRGB afterwards.
+    pub space: Space,
+    /// The seed of the random number generator of the first run. Each following run uses the
+    /// next seed.
+    pub seed: u64,
+    /// The maximum number of iterations of each run.
+    pub max_iterations: usize,
+    /// A run stops when the centroids moved less than this since the previous iteration.
+    pub converge: f32,
+    /// The number of runs, the one with the lowest error being kept.
+    pub runs: u64,
+    /// Whether to snap the centroids to distinct 15-bit colors after each iteration, so that
+    /// k-means optimizes the colors which are really displayed, and no two clusters end up with
+    /// the same color.
+    pub snap: bool,
+}
+
+/// Counts the occurrences of each unique color.
+///
+/// # Returns
+///
+/// A `Vec` of the unique colors with their number of occurrences, in order of first occurrence.
+pub fn color_histogram(colors: impl IntoIterator<Item = Rgb<u8>>) -> Vec<(Rgb<u8>, u32)> {
+    let mut indices = HashMap::new();
+    let mut histogram = Vec::<(Rgb<u8>, u32)>::new();
+    for color in colors {
+        let index = *indices.entry(color).or_insert_with(|| {
+            histogram.push((color, 0));
+            histogram.len() - 1
+        });
+        histogram[index].1 += 1;
+    }
+    histogram
+}
+
+impl Quantizer for KmeansOptions {
+    /// Clusters colors with k-means and returns the centroids.
+    ///
+    /// Each color of the histogram weighs as much as its number of occurrences, which gives
+    /// approximately the same centroids as clustering every pixel, with far fewer points: only the
+    /// first initial centroid is picked differently, uniformly among the unique colors rather than
+    /// the pixels. k-means is run `self.runs` times with consecutive seeds, and the centroids of
+    /// the run with the lowest sum of squared distances between the points and their centroid are
+    /// returned.
+    fn quantize(&self, histogram: &[(Rgb<u8>, u32)], num_colors: usize) -> Vec<[f32; 3]> {
+        if histogram.is_empty() {
+            return Vec::new();
+        }
+
+        let points = to_points(histogram, self);
+        let centroids = (0..self.runs.max(1))
+            .map(|run| {
+                let seed = self.seed.wrapping_add(run);
+                let kmeans = get_kmeans(
+                    num_colors,
+                    self.max_iterations,
+                    self.converge,
+                    false,
+                    &points,
+                    seed,
+                );
+                let error = points
+                    .iter()
+                    .zip(&kmeans.indices)
+                    .map(|(point, &index)| {
+                        WeightedPoint::difference(point, &kmeans.centroids[index as usize])
+                    })
+                    .sum::<f32>();
+                (kmeans.centroids, error)
+            })
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(centroids, _)| centroids)
+            .unwrap_or_default();
+
+        centroids
+            .iter()
+            .map(|centroid| self.space.to_srgb(centroid.color))
+            .collect()
+    }
+}
+
+/// Finds a palette of 15-bit colors for the colors of a histogram, which starts with the given
+/// colors.
+///
+/// # Arguments
+///
+/// - `locked` - The distinct 15-bit colors which must be in the palette.
+/// - `histogram` - The unique colors to be represented with their number of occurrences, as
+///   returned by `color_histogram`.
+/// - `num_colors` - The maximum number of colors in the resulting palette.
+/// - `options` - The parameters of the k-means clustering.
+///
+/// # Algorithm
+///
+/// k-means is run with the locked colors as centroids which never move. The other centroids are
+/// picked with k-means++, as if the locked colors had been picked first, so that they go to the
+/// colors the locked colors represent worst. As in `KmeansOptions::quantize`, the run with the
+/// lowest error is kept, and its centroids are rounded to 15-bit, deduplicated and refilled with
+/// `refill_palette`.
+///
+/// # Returns
+///
+/// A `Vec` of distinct `Rgb` colors starting with `locked`. The palette may have fewer than
+/// `num_colors` entries if there aren't enough distinct colors, and has more if `locked` does.
+pub fn lock_palette(
+    locked: &[Rgb<u8>],
+    histogram: &[(Rgb<u8>, u32)],
+    num_colors: usize,
+    options: &KmeansOptions,
+) -> Vec<Rgb<u8>> {
+    let mut palette = locked.to_vec();
+    if histogram.is_empty() || palette.len() >= num_colors {
+        return palette;
+    }
+
+    let points = to_points(histogram, options);
+    let locked_centroids = locked
+        .iter()
+        .map(|color| WeightedPoint {
+            color: options.space.to_point(color),
+            ..WeightedPoint::default()
+        })
+        .collect::<Vec<_>>();
+    let centroids = (0..options.runs.max(1))
+        .map(|run| {
+            let mut rng = StdRng::seed_from_u64(options.seed.wrapping_add(run));
+            let mut centroids = locked_centroids.clone();
+            while centroids.len() < num_colors {
+                let distances = points.iter().map(|point| {
+                    centroids
+                        .iter()
+                        .map(|centroid| WeightedPoint::difference(point, centroid))
+                        .min_by(f32::total_cmp)
+                        .unwrap_or(point.weight)
+                });
+                // every color is already a centroid
+                let Ok(distribution) = WeightedIndex::new(distances) else {
+                    break;
+                };
+                centroids.push(points[distribution.sample(&mut rng)]);
+            }
+
+            let mut indices = Vec::with_capacity(points.len());
+            for iteration in 0.. {
+                indices.clear();
+                WeightedPoint::get_closest_centroid(&points, &centroids, &mut indices);
+                let old_centroids = centroids.clone();
+                WeightedPoint::recalculate_centroids(&mut rng, &points, &mut centroids, &indices);
+                centroids[..locked.len()].copy_from_slice(&locked_centroids);
+                let movement = WeightedPoint::check_loop(&centroids, &old_centroids);
+                if iteration >= options.max_iterations || movement <= options.converge {
+                    break;
+                }
+            }
+
+            indices.clear();
+            WeightedPoint::get_closest_centroid(&points, &centroids, &mut indices);
+            let error = points
+                .iter()
+                .zip(&indices)
+                .map(|(point, &index)| WeightedPoint::difference(point, &centroids[index as usize]))
+                .sum::<f32>();
+            (centroids, error)
+        })
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(centroids, _)| centroids)
+        .unwrap_or_default();
+
+    for centroid in &centroids[locked.len()..] {
+        let color = options.space.to_15bit(centroid.color);
+        if !palette.contains(&color) && palette.len() < num_colors {
+            palette.push(color);
+        }
+    }
+    refill_palette(&mut palette, histogram, num_colors, locked.len(), options);
+    palette
+}
+
+/// Splits the clusters with the highest error in two until the palette has `num_colors` distinct
+/// colors, to make up for colors which became the same 15-bit color.
+///
+/// Each color of the histogram belongs to the cluster of its closest palette color in
+/// `options.space`, and the cluster with the highest sum of squared distances is split by running
+/// k-means with two centroids on its colors.
+/// If both halves round to existing colors, the color of the point of the cluster which is worst
+/// represented and still missing from the palette is added instead. A cluster without such a
+/// point is left as is, and the next worst one is tried.
+///
+/// The first `locked` colors of the palette are never replaced: splitting their cluster only adds
+/// the halves next to them.
+pub fn refill_palette(
+    palette: &mut Vec<Rgb<u8>>,
+    histogram: &[(Rgb<u8>, u32)],
+    num_colors: usize,
+    locked: usize,
+    options: &KmeansOptions,
+) {
+    let points = to_points(histogram, options);
+    let mut unsplittable = HashSet::new();
+    while palette.len() < num_colors {
+        let centroids = palette
+            .iter()
+            .map(|color| WeightedPoint {
+                color: options.space.to_point(color),
+                ..WeightedPoint::default()
+            })
+            .collect::<Vec<_>>();
+        let mut indices = Vec::with_capacity(points.len());
+        WeightedPoint::get_closest_centroid(&points, &centroids, &mut indices);
+
+        let mut errors = vec![0.0; palette.len()];
+        for (point, &index) in points.iter().zip(&indices) {
+            errors[index as usize] += WeightedPoint::difference(point, &centroids[index as usize]);
+        }
+        let worst = errors
+            .iter()
+            .enumerate()
+            .filter(|&(index, &error)| error > 0.0 && !unsplittable.contains(&palette[index]))
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(index, _)| index);
+        let Some(worst) = worst else {
+            return;
+        };
+
+        let cluster = points
+            .iter()
+            .zip(&indices)
+            .filter(|(_, &index)| index as usize == worst)
+            .map(|(&point, _)| point)
+            .collect::<Vec<_>>();
+        let kmeans = get_kmeans(
+            2,
+            options.max_iterations,
+            options.converge,
+            false,
+            &cluster,
+            options.seed,
+        );
+
+        let mut split_palette = palette.clone();
+        if worst >= locked {
+            split_palette.remove(worst);
+        }
+        for centroid in &kmeans.centroids {
+            let color = options.space.to_15bit(centroid.color);
+            if !split_palette.contains(&color) && split_palette.len() < num_colors {
+                split_palette.push(color);
+            }
+        }
+        if split_palette.len() > palette.len() {
+            *palette = split_palette;
+            continue;
+        }
+
+        // the halves rounded to existing colors, so add the worst represented new color instead
+        let farthest = cluster
+            .iter()
+            .map(|point| (point, options.space.to_15bit(point.color)))
+            .filter(|(_, color)| !palette.contains(color))
+            .max_by(|(a, _), (b, _)| {
+                let centroid = &centroids[worst];
+                WeightedPoint::difference(a, centroid)
+                    .total_cmp(&WeightedPoint::difference(b, centroid))
+            });
+        match farthest {
+            Some((_, color)) => palette.push(color),
+            None => {
+                unsplittable.insert(palette[worst]);
+            }
+        }
+    }
+}
+
+/// Converts the colors of a histogram to weighted points in the color space of the clustering.
+fn to_points(histogram: &[(Rgb<u8>, u32)], options: &KmeansOptions) -> Vec<WeightedPoint> {
+    histogram
+        .iter()
+        .map(|&(color, count)| WeightedPoint {
+            color: options.space.to_point(&color),
+            weight: count as f32,
+            snap: options.snap.then_some(options.space),
+        })
+        .collect()
+}
+
+impl From<Metric> for Space {
+    /// Returns the color space the metric works in, i.e. sRGB for `Metric::Rgb` and
+    /// `Metric::Redmean`, CIELAB for `Metric::Lab` and `Metric::Ciede2000`, and OKLab for
+    /// `Metric::Oklab`.
+    fn from(metric: Metric) -> Self {
+        match metric {
+            Metric::Rgb | Metric::Redmean => Space::Srgb,
+            Metric::Lab | Metric::Ciede2000 => Space::Lab,
+            Metric::Oklab => Space::Oklab,
+        }
+    }
+}
+
+impl Space {
+    /// Converts a color to its components in this color space, sRGB components being in
+    /// `0.0..=1.0`.
+    fn to_point(self, color: &Rgb<u8>) -> [f32; 3] {
+        let srgb = Srgb::new(color[0], color[1], color[2]).into_format::<f32>();
+        match self {
+            Space::Srgb => [srgb.red, srgb.green, srgb.blue],
+            Space::Lab => {
+                let lab: Lab = Lab::from_color(srgb);
+                [lab.l, lab.a, lab.b]
+            }
+            Space::Oklab => {
+                let oklab = Oklab::from_color(srgb);
+                [oklab.l, oklab.a, oklab.b]
+            }
+        }
+    }
+
+    /// Converts components in this color space back to sRGB, with 8-bit channels which may be
+    /// fractional or out of range.
+    fn to_srgb(self, [x, y, z]: [f32; 3]) -> [f32; 3] {
+        let srgb = match self {
+            Space::Srgb => Srgb::new(x, y, z),
+            Space::Lab => Srgb::from_color(Lab::new(x, y, z)),
+            Space::Oklab => Srgb::from_color(Oklab::new(x, y, z)),
+        };
+        [srgb.red, srgb.green, srgb.blue].map(|channel| channel * 255.0)
+    }
+
+    /// Converts components in this color space back to sRGB, rounded to the closest 15-bit color.
+    fn to_15bit(self, point: [f32; 3]) -> Rgb<u8> {
+        to_15bit(self.to_srgb(point))
+    }
+}
+
+/// A color in any color space which k-means can be run on, with the Euclidean distance, standing
+/// for `weight` pixels of that color.
+///
+/// `difference` scales the squared distance by the weight of the first point, which is the pixel
+/// in k-means++ initialization, so that the initial centroids after the first one are picked with
+/// a probability proportional to the number of pixels. The first one is picked uniformly among the
+/// unique colors by `kmeans_colors`, so the initialization only approximates clustering every
+/// pixel.
+#[derive(Debug, Clone, Copy, Default)]
+struct WeightedPoint {
+    color: [f32; 3],
+    weight: f32,
+    /// The color space of `color`, if centroids are snapped to distinct 15-bit colors.
+    snap: Option<Space>,
+}
+
+impl WeightedPoint {
+    fn distance(&self, other: &Self) -> f32 {
+        (0..3)
+            .map(|c| (self.color[c] - other.color[c]).powi(2))
+            .sum()
+    }
+}
+
+impl Calculate for WeightedPoint {
+    fn get_closest_centroid(buffer: &[Self], centroids: &[Self], indices: &mut Vec<u8>) {
+        indices.extend(buffer.iter().map(|point| {
+            centroids
+                .iter()
+                .map(|centroid| point.distance(centroid))
+                .enumerate()
+                .min_by(|(_, a), (_, b)| a.total_cmp(b))
+                .map_or(0, |(index, _)| index as u8)
+        }));
+    }
+
+    /// Moves each centroid to the weighted mean of its points, then snaps it to the closest 15-bit
+    /// color which isn't taken by a previous centroid if the points ask for it. A centroid without
+    /// any point stays where it is.
+    fn recalculate_centroids(
+        _: &mut impl Rng,
+        buffer: &[Self],
+        centroids: &mut [Self],
+        indices: &[u8],
+    ) {
+        for (index, centroid) in centroids.iter_mut().enumerate() {
+            let (sum, weight) = indices
+                .iter()
+                .zip(buffer)
+                .filter(|(&i, _)| i as usize == index)
+                .fold(([0.0; 3], 0.0), |(sum, weight), (_, point)| {
+                    let sum = [0, 1, 2].map(|c| sum[c] + point.color[c] * point.weight);
+                    (sum, weight + point.weight)
+                });
+            if weight > 0.0 {
+                centroid.color = sum.map(|c| c / weight);
+            }
+        }
+
+        if let Some(space) = buffer.first().and_then(|point| point.snap) {
+            snap_centroids(centroids, space);
+        }
+    }
+
+    fn check_loop(centroids: &[Self], old_centroids: &[Self]) -> f32 {
+        centroids
+            .iter()
+            .zip(old_centroids)
+            .map(|(centroid, old)| centroid.distance(old))
+            .sum()
+    }
+
+    fn create_random(rng: &mut impl Rng) -> Self {
+        WeightedPoint {
+            color: [rng.gen(), rng.gen(), rng.gen()],
+            weight: 1.0,
+            snap: None,
+        }
+    }
+
+    fn difference(first: &Self, second: &Self) -> f32 {
+        first.weight * first.distance(second)
+    }
+}
+
+/// Moves each centroid to the closest 15-bit color, in the given color space, which isn't taken by
+/// a previous centroid.
+///
+/// The 15-bit color closest to the centroid is tried first, then the colors in growing cubes
+/// around it, until one of them is free.
+fn snap_centroids(centroids: &mut [WeightedPoint], space: Space) {
+    let mut taken = HashSet::new();
+    for centroid in centroids {
+        let center = space
+            .to_15bit(centroid.color)
+            .0
+            .map(|channel| to_5bit(channel as f32) as i32);
+        for radius in 0..32 {
+            let range = |channel: usize| {
+                (center[channel] - radius).max(0) as u8..=(center[channel] + radius).min(31) as u8
+            };
+            let closest = range(0)
+                .flat_map(|r| range(1).map(move |g| (r, g)))
+                .flat_map(|(r, g)| range(2).map(move |b| [r, g, b]))
+                .filter(|color| !taken.contains(color))
+                .map(|color| {
+                    let point = space.to_point(&Rgb(color.map(from_5bit)));
+                    let distance = (0..3).map(|c| (point[c] - centroid.color[c]).powi(2)).sum();
+                    (color, point, distance)
+                })
+                .min_by(|(_, _, a): &(_, _, f32), (_, _, b)| a.total_cmp(b));
+            if let Some((color, point, _)) = closest {
+                taken.insert(color);
+                centroid.color = point;
+                break;
+            }
+        }
+    }
+}
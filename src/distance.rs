@@ -0,0 +1,66 @@
+use image::Rgb;
+use palette::{color_difference::Ciede2000, FromColor, Lab, Oklab, Srgb};
+
+use crate::args::Metric;
+
+impl Metric {
+    /// Converts a color to the color space this metric works in, so that it can be compared with
+    /// `Metric::compute_prepared_distance` without being converted again.
+    ///
+    /// The components are sRGB in `0.0..=255.0` for `Metric::Rgb` and `Metric::Redmean`, CIELAB for
+    /// `Metric::Lab` and `Metric::Ciede2000`, and OKLab for `Metric::Oklab`.
+    pub fn prepare(self, color: &Rgb<u8>) -> [f32; 3] {
+        let srgb = Srgb::new(color[0], color[1], color[2]).into_format::<f32>();
+        match self {
+            Metric::Rgb | Metric::Redmean => color.0.map(f32::from),
+            Metric::Lab | Metric::Ciede2000 => {
+                let lab: Lab = Lab::from_color(srgb);
+                [lab.l, lab.a, lab.b]
+            }
+            Metric::Oklab => {
+                let oklab = Oklab::from_color(srgb);
+                [oklab.l, oklab.a, oklab.b]
+            }
+        }
+    }
+
+    /// Computes the squared distance between two colors prepared with `Metric::prepare`.
+    ///
+    /// - `Metric::Rgb`, `Metric::Lab` and `Metric::Oklab` use the squared Euclidean distance in
+    ///   their color space, the latter being CIE76 ΔE\* for CIELAB.
+    /// - `Metric::Redmean` weights the red, green and blue differences depending on the mean red
+    ///   level of both colors, which approximates perceived differences in sRGB.
+    /// - `Metric::Ciede2000` uses the square of the CIEDE2000 ΔE\*.
+    pub fn compute_prepared_distance(self, first: &[f32; 3], second: &[f32; 3]) -> f32 {
+        let [d0, d1, d2] = [0, 1, 2].map(|i| first[i] - second[i]);
+        match self {
+            Metric::Rgb | Metric::Lab | Metric::Oklab => d0 * d0 + d1 * d1 + d2 * d2,
+            Metric::Redmean => {
+                let red_mean = (first[0] + second[0]) / 2.0;
+                (2.0 + red_mean / 256.0) * d0 * d0
+                    + 4.0 * d1 * d1
+                    + (2.0 + (255.0 - red_mean) / 256.0) * d2 * d2
+            }
+            Metric::Ciede2000 => {
+                let first: Lab = Lab::new(first[0], first[1], first[2]);
+                let second: Lab = Lab::new(second[0], second[1], second[2]);
+                first.difference(second).powi(2)
+            }
+        }
+    }
+
+    /// Computes the squared distance between two colors.
+    ///
+    /// # Arguments
+    ///
+    /// * `first_color` - An Rgb<u8> color.
+    /// * `second_color` - An Rgb<u8> color.
+    ///
+    /// # Returns
+    ///
+    /// * An `f32` - The squared distance according to this metric. Distances computed with
+    ///   different metrics are not comparable.
+    pub fn compute_squared_distance(self, first_color: &Rgb<u8>, second_color: &Rgb<u8>) -> f32 {
+        self.compute_prepared_distance(&self.prepare(first_color), &self.prepare(second_color))
+    }
+}
@@ -0,0 +1,211 @@
+use image::{Rgb, Rgba};
+
+use crate::{
+    args::{Dither, Metric},
+    find_closest_color, Image,
+};
+
+/// Side of the blue noise threshold map.
+const BLUE_NOISE_SIZE: usize = 16;
+
+/// Blue noise threshold map, generated with the void-and-cluster method. Each value from 0 to 255
+/// appears once, and values close to each other are spread evenly over the map, so that the
+/// dithering pattern has no visible structure.
+#[rustfmt::skip]
+const BLUE_NOISE: [[u8; BLUE_NOISE_SIZE]; BLUE_NOISE_SIZE] = [
+    [120,  61, 134, 223,  84,  33, 168,  12, 113, 225,  63, 246, 185, 233,  88, 169],
+    [ 23, 206, 181,  17, 109, 214,  58, 140, 201,  24, 161,  93,  34, 133,  14, 221],
+    [144,  73, 250,  49, 158, 187,  81, 251, 100,  51, 142, 210, 172,  57, 191, 106],
+    [ 42, 167, 101, 126, 220,   3, 121,  40, 170, 231,  82,   8, 114, 255,  80, 232],
+    [212,  11, 195,  31,  72, 239, 152, 196,  16, 127, 188, 222,  45, 157,  26, 128],
+    [154,  87, 235, 143, 179,  94,  54, 108, 237,  65,  29, 105, 139, 207, 184,  66],
+    [248,  47, 115,  62, 209,  20, 164, 217,  79, 146, 178, 243,  69,  90,   1, 118],
+    [ 30, 190, 173,   6, 131, 254,  41, 136,  10, 204,  43, 159,  22, 229, 162, 218],
+    [ 77, 148,  99, 226,  74, 182, 117, 192,  86, 247, 119,  97, 197, 130,  53, 103],
+    [242,  19, 198,  44, 155,  96,  59, 230,  28, 165,  60,   5, 240,  39, 175, 202],
+    [137,  64, 122, 238,  25, 211,   0, 149, 104, 224, 135, 183, 151,  71, 112,   9],
+    [ 91, 213, 166,  85, 186, 111, 249, 174,  48,  75, 208,  32,  89, 205, 236, 160],
+    [ 37, 252,  18,  55, 138,  38,  78, 123, 194,  13, 107, 253, 124,  15,  56, 189],
+    [ 76, 145, 110, 228, 203, 163, 219,  21, 241, 141, 171,  50, 156, 227, 102, 129],
+    [  2, 199, 176,  68,   7,  98,  52, 150,  92,  36, 215,  83, 200,  27, 177, 216],
+    [244,  95,  35, 153, 245, 125, 193, 234,  70, 180, 132,   4, 116,  67, 147,  46],
+];
+
+/// An error diffusion kernel, as `(dx, dy, weight)` offsets from the current pixel, and the
+/// divisor of the weights.
+type Kernel = (&'static [(i32, i32, f32)], f32);
+
+/// How to dither an image when reducing its colors.
+#[derive(Debug, Clone, Copy)]
+pub struct DitherOptions {
+    /// The error diffusion kernel or ordered dithering threshold map.
+    pub dither: Dither,
+    /// Whether to process every other row from right to left, which avoids the diagonal artifacts
+    /// of always diffusing the error in the same direction. Error diffusion only.
+    pub serpentine: bool,
+    /// The fraction of the error which is diffused, from `0.0` (no dithering) to `1.0`. Error
+    /// diffusion only.
+    pub strength: f32,
+    /// The range by which colors are offset by the threshold map, in 8-bit units. Ordered
+    /// dithering only.
+    pub spread: f32,
+}
+
+impl Dither {
+    /// Returns the error diffusion kernel, or `None` for ordered dithering.
+    fn kernel(self) -> Option<Kernel> {
+        Some(match self {
+            Dither::FloydSteinberg => {
+                (&[(1, 0, 7.0), (-1, 1, 3.0), (0, 1, 5.0), (1, 1, 1.0)], 16.0)
+            }
+            // Atkinson diffuses only 6/8 of the error, which keeps more contrast
+            Dither::Atkinson => (
+                &[
+                    (1, 0, 1.0),
+                    (2, 0, 1.0),
+                    (-1, 1, 1.0),
+                    (0, 1, 1.0),
+                    (1, 1, 1.0),
+                    (0, 2, 1.0),
+                ],
+                8.0,
+            ),
+            Dither::JarvisJudiceNinke => (
+                &[
+                    (1, 0, 7.0),
+                    (2, 0, 5.0),
+                    (-2, 1, 3.0),
+                    (-1, 1, 5.0),
+                    (0, 1, 7.0),
+                    (1, 1, 5.0),
+                    (2, 1, 3.0),
+                    (-2, 2, 1.0),
+                    (-1, 2, 3.0),
+                    (0, 2, 5.0),
+                    (1, 2, 3.0),
+                    (2, 2, 1.0),
+                ],
+                48.0,
+            ),
+            Dither::Sierra => (
+                &[
+                    (1, 0, 5.0),
+                    (2, 0, 3.0),
+                    (-2, 1, 2.0),
+                    (-1, 1, 4.0),
+                    (0, 1, 5.0),
+                    (1, 1, 4.0),
+                    (2, 1, 2.0),
+                    (-1, 2, 2.0),
+                    (0, 2, 3.0),
+                    (1, 2, 2.0),
+                ],
+                32.0,
+            ),
+            Dither::Bayer2 | Dither::Bayer4 | Dither::Bayer8 | Dither::BlueNoise => return None,
+        })
+    }
+
+    /// Returns the ordered dithering threshold at the given coordinates, between `-0.5` and
+    /// `0.5`. The threshold map is tiled over the image.
+    fn threshold(self, x: u32, y: u32) -> f32 {
+        let (value, count) = match self {
+            Dither::Bayer2 => (bayer(x, y, 1), 4),
+            Dither::Bayer4 => (bayer(x, y, 2), 16),
+            Dither::Bayer8 => (bayer(x, y, 3), 64),
+            _ => {
+                let (x, y) = (x as usize % BLUE_NOISE_SIZE, y as usize % BLUE_NOISE_SIZE);
+                (BLUE_NOISE[y][x] as u32, BLUE_NOISE_SIZE * BLUE_NOISE_SIZE)
+            }
+        };
+        (value as f32 + 0.5) / count as f32 - 0.5
+    }
+}
+
+/// Returns the value of the Bayer matrix of side `2^order` at the given coordinates.
+///
+/// The matrix of side `2n` is made of four copies of the matrix of side `n`, multiplied by 4 and
+/// offset by the values of the 2x2 matrix `[[0, 2], [3, 1]]`.
+fn bayer(x: u32, y: u32, order: u32) -> u32 {
+    (0..order).fold(0, |value, bit| {
+        let (x, y) = ((x >> bit) & 1, (y >> bit) & 1);
+        value | (2 * (x ^ y) + y) << (2 * (order - 1 - bit))
+    })
+}
+
+/// Reduces the colors of an image with dithering. The pixels of the image are changed in place to
+/// a color of the palette, so that the average color of an area approximates the original, and
+/// gradients don't turn into bands.
+///
+/// # Arguments
+///
+/// - `image` - A mutable reference to the image that will be reduced in colors.
+/// - `options` - The dithering method and its parameters.
+/// - `metric` - The color distance metric used to find the closest palette color.
+/// - `palette_at` - Returns the palette available at the given pixel coordinates, e.g.
+///   the palette of the tile containing it.
+///
+/// # Algorithm
+///
+/// With error diffusion, the quantization error of each pixel is spread to its neighbours which
+/// haven't been processed yet, according to the kernel. With ordered dithering, each pixel is
+/// offset by the value of the threshold map at its position, scaled by the spread, before its
+/// closest color is found.
+pub fn dither_colors<'a>(
+    image: &mut Image,
+    options: &DitherOptions,
+    metric: Metric,
+    palette_at: impl Fn(u32, u32) -> &'a [Rgb<u8>],
+) {
+    let (width, height) = image.dimensions();
+
+    // the colors of the pixels, with the error diffused so far
+    let mut colors = image
+        .pixels()
+        .map(|pixel| [pixel[0], pixel[1], pixel[2]].map(f32::from))
+        .collect::<Vec<_>>();
+
+    let Some((kernel, divisor)) = options.dither.kernel() else {
+        for (y, x) in (0..height).flat_map(|y| (0..width).map(move |x| (y, x))) {
+            let offset = options.dither.threshold(x, y) * options.spread;
+            let color =
+                colors[(y * width + x) as usize].map(|c| (c + offset).round().clamp(0.0, 255.0));
+            let closest =
+                find_closest_color(palette_at(x, y), &Rgb(color.map(|c| c as u8)), metric);
+            set_color(image, x, y, closest);
+        }
+        return;
+    };
+
+    for y in 0..height {
+        let reversed = options.serpentine && y % 2 == 1;
+        for i in 0..width {
+            let x = if reversed { width - 1 - i } else { i };
+            let color = colors[(y * width + x) as usize].map(|c| c.round().clamp(0.0, 255.0));
+            let closest =
+                find_closest_color(palette_at(x, y), &Rgb(color.map(|c| c as u8)), metric);
+            let error =
+                [0, 1, 2].map(|c| (color[c] - closest[c] as f32) * options.strength / divisor);
+
+            for &(dx, dy, weight) in kernel {
+                let dx = if reversed { -dx } else { dx };
+                let (nx, ny) = (x as i32 + dx, y as i32 + dy);
+                if nx < 0 || nx >= width as i32 || ny >= height as i32 {
+                    continue;
+                }
+                let neighbour = &mut colors[(ny as u32 * width + nx as u32) as usize];
+                for c in 0..3 {
+                    neighbour[c] += error[c] * weight;
+                }
+            }
+
+            set_color(image, x, y, closest);
+        }
+    }
+}
+
+/// Sets the color of a pixel, keeping its alpha.
+fn set_color(image: &mut Image, x: u32, y: u32, color: Rgb<u8>) {
+    let pixel = image.get_pixel_mut(x, y);
+    *pixel = Rgba([color[0], color[1], color[2], pixel[3]]);
+}
@@ -0,0 +1,167 @@
+use image::{Rgb, Rgba};
+
+use crate::{
+    args::{Preset, Shades},
+    Image,
+};
+
+/// Number of shades the original Game Boy can display.
+pub const NUM_SHADES: usize = 4;
+
+/// Maximum number of iterations of the k-means clustering of luma values.
+const MAX_ITERATIONS: usize = 100;
+
+/// k-means stops when no level moves by more than this, in luma values.
+const CONVERGE: f32 = 0.01;
+
+impl Preset {
+    /// Returns the colors of the shades of a preset, from the darkest to the lightest.
+    pub fn ramp(self) -> [Rgb<u8>; NUM_SHADES] {
+        let colors = match self {
+            Preset::PeaGreen => [0x0f380f, 0x306230, 0x8bac0f, 0x9bbc0f],
+            Preset::Pocket => [0x1f1f1f, 0x4d533c, 0x8b956d, 0xc4cfa1],
+            Preset::Light => [0x004f3b, 0x00694a, 0x009a71, 0x00b581],
+        };
+        colors.map(|color: u32| Rgb([(color >> 16) as u8, (color >> 8) as u8, color as u8]))
+    }
+}
+
+/// Converts the pixels of an image to gray, with their luma as defined by ITU-R BT.601 on the
+/// gamma-encoded channels. Alpha is kept.
+pub fn to_grayscale(image: &mut Image) {
+    for pixel in image.pixels_mut() {
+        let luma = luma(pixel);
+        *pixel = Rgba([luma, luma, luma, pixel[3]]);
+    }
+}
+
+/// Finds the gray levels of the shades of an image converted with `to_grayscale`, which is then
+/// reduced to them with `reduce_colors`, matching the exact luma of each pixel.
+///
+/// # Arguments
+///
+/// - `image` - A reference to the grayscale image.
+/// - `shades` - How the shades are chosen:
+///   - `Shades::Thresholds` splits the luma into 4 equal ranges, whose middles are the levels, so
+///     that the closest level of a pixel is the range it falls in.
+///   - `Shades::Kmeans` clusters the luma of the pixels with k-means.
+/// - `transparent` - Whether transparent pixels are taken into account with `Shades::Kmeans`.
+///
+/// # Algorithm
+///
+/// k-means runs on the histogram of the 256 luma values, from the levels which split the pixels
+/// into 4 groups of the same size. Each iteration moves each level to the mean luma of the
+/// pixels which are closer to it than to the other levels, until no level moves by more than
+/// `CONVERGE` or `MAX_ITERATIONS` is reached. These are independent of `--max-iterations` and
+/// `--converge`, which apply to the k-means clustering of colors.
+///
+/// # Returns
+///
+/// Up to `NUM_SHADES` distinct gray levels, from the darkest to the lightest. There are fewer
+/// levels only if the image has fewer distinct luma values.
+pub fn find_shades(image: &Image, shades: Shades, transparent: bool) -> Vec<Rgb<u8>> {
+    let levels = match shades {
+        Shades::Thresholds => (0..NUM_SHADES)
+            .map(|shade| ((shade * 2 + 1) * 256 / (NUM_SHADES * 2)) as f32)
+            .collect(),
+        Shades::Kmeans => {
+            let mut histogram = [0u64; 256];
+            for pixel in image.pixels() {
+                if transparent || pixel[3] == 255 {
+                    histogram[pixel[0] as usize] += 1;
+                }
+            }
+            cluster_luma(&histogram)
+        }
+    };
+
+    let mut shades = Vec::<Rgb<u8>>::with_capacity(NUM_SHADES);
+    for level in levels {
+        let level = level.round() as u8;
+        if !shades.iter().any(|shade| shade[0] == level) {
+            shades.push(Rgb([level, level, level]));
+        }
+    }
+    shades
+}
+
+/// Replaces the gray levels of an image reduced to the shades found by `find_shades` with the
+/// colors of a ramp, from the darkest to the lightest. If there are fewer than `NUM_SHADES`
+/// levels, each level takes the color of the range it falls in, like with `Shades::Thresholds`.
+pub fn apply_ramp(image: &mut Image, shades: &[Rgb<u8>], ramp: &[Rgb<u8>; NUM_SHADES]) {
+    for pixel in image.pixels_mut() {
+        let level = pixel[0];
+        let shade = match shades.len() {
+            NUM_SHADES => shades.iter().position(|shade| shade[0] == level),
+            _ => None,
+        }
+        .unwrap_or(level as usize * NUM_SHADES / 256);
+        let color = ramp[shade];
+        *pixel = Rgba([color[0], color[1], color[2], pixel[3]]);
+    }
+}
+
+fn luma(pixel: &Rgba<u8>) -> u8 {
+    (0.299 * pixel[0] as f32 + 0.587 * pixel[1] as f32 + 0.114 * pixel[2] as f32).round() as u8
+}
+
+/// Clusters a histogram of luma values into `NUM_SHADES` levels with k-means, and returns the
+/// levels of the clusters which aren't empty, in increasing order.
+fn cluster_luma(histogram: &[u64; 256]) -> Vec<f32> {
+    let total = histogram.iter().sum::<u64>();
+    if total == 0 {
+        return Vec::new();
+    }
+
+    // start from the luma values at the middle of each quarter of the pixels
+    let mut cumulative = 0;
+    let mut levels = Vec::with_capacity(NUM_SHADES);
+    for (luma, &count) in histogram.iter().enumerate() {
+        cumulative += count;
+        while levels.len() < NUM_SHADES
+            && cumulative * 2 * NUM_SHADES as u64 > total * (levels.len() * 2 + 1) as u64
+        {
+            levels.push(luma as f32);
+        }
+    }
+
+    // the sum and number of the luma values of the pixels closest to each level
+    let assign = |levels: &[f32]| {
+        let mut sums = vec![(0.0, 0u64); NUM_SHADES];
+        for (luma, &count) in histogram.iter().enumerate().filter(|(_, &count)| count > 0) {
+            let closest = (0..NUM_SHADES)
+                .min_by(|&a, &b| {
+                    (levels[a] - luma as f32)
+                        .abs()
+                        .total_cmp(&(levels[b] - luma as f32).abs())
+                })
+                .unwrap_or(0);
+            sums[closest].0 += luma as f64 * count as f64;
+            sums[closest].1 += count;
+        }
+        sums
+    };
+
+    for _ in 0..MAX_ITERATIONS {
+        let sums = assign(&levels);
+        let mut moved = 0.0f32;
+        for (level, (sum, count)) in levels.iter_mut().zip(sums) {
+            if count > 0 {
+                let mean = (sum / count as f64) as f32;
+                moved = moved.max((mean - *level).abs());
+                *level = mean;
+            }
+        }
+        if moved <= CONVERGE {
+            break;
+        }
+    }
+
+    let sums = assign(&levels);
+    levels
+        .into_iter()
+        .zip(sums)
+        .filter(|&(_, (_, count))| count > 0)
+        .map(|(level, _)| level)
+        .collect()
+}
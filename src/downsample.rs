@@ -0,0 +1,172 @@
+use std::{collections::HashMap, ops::Range};
+
+use image::{
+    imageops::{resize, FilterType},
+    Rgba,
+};
+
+use crate::{args::Filter, Image};
+
+/// Resizes an image to the given size with a downsampling filter.
+///
+/// # Arguments
+///
+/// - `image` - A reference to the image to be resized.
+/// - `width` - The width of the resized image, in pixels.
+/// - `height` - The height of the resized image, in pixels.
+/// - `filter` - How the pixels covered by each resized pixel are combined:
+///   - `Filter::Nearest` picks one of them.
+///   - `Filter::Area` averages them, weighted by how much of each is covered.
+///   - `Filter::Triangle`, `Filter::CatmullRom`, `Filter::Lanczos3` and `Filter::Gaussian` use the
+///     resampling filters of the `image` crate, which also weigh in some neighbouring pixels.
+///   - `Filter::Median` takes the median of each channel.
+///   - `Filter::Mode` takes the most frequent color, the first one found in case of a tie.
+///
+/// Colors are averaged with premultiplied alpha, so that the color of transparent pixels doesn't
+/// bleed into their neighbours. When upscaling, `Filter::Median` and `Filter::Mode` are the same as
+/// `Filter::Nearest`.
+///
+/// # Returns
+///
+/// The resized image.
+pub fn downsample(image: &Image, width: u32, height: u32, filter: Filter) -> Image {
+    let filter_type = match filter {
+        Filter::Area => return area(image, width, height),
+        Filter::Median => return per_block(image, width, height, median),
+        Filter::Mode => return per_block(image, width, height, mode),
+        Filter::Nearest => FilterType::Nearest,
+        Filter::Triangle => FilterType::Triangle,
+        Filter::CatmullRom => FilterType::CatmullRom,
+        Filter::Lanczos3 => FilterType::Lanczos3,
+        Filter::Gaussian => FilterType::Gaussian,
+    };
+    resize(image, width, height, filter_type)
+}
+
+/// Resizes an image by averaging the area of the image covered by each resized pixel.
+///
+/// The average is computed along rows, then along columns, since the area covered by a pixel is
+/// the product of the ranges it covers along each axis.
+fn area(image: &Image, width: u32, height: u32) -> Image {
+    let columns = coverage(image.width(), width);
+    let rows = coverage(image.height(), height);
+
+    // premultiplied colors, averaged along each row
+    let mut averaged = vec![[0.0f32; 4]; width as usize * image.height() as usize];
+    for (y, row) in image.rows().enumerate() {
+        let row = row.map(premultiply).collect::<Vec<_>>();
+        for (x, sources) in columns.iter().enumerate() {
+            averaged[y * width as usize + x] = average(sources.iter().map(|&(s, w)| (row[s], w)));
+        }
+    }
+
+    Image::from_fn(width, height, |x, y| {
+        let pixel = average(
+            rows[y as usize]
+                .iter()
+                .map(|&(s, w)| (averaged[s * width as usize + x as usize], w)),
+        );
+        unpremultiply(pixel)
+    })
+}
+
+/// Returns, for each pixel of a resized axis, the indices of the source pixels it covers and the
+/// fraction of each which is covered.
+fn coverage(source: u32, destination: u32) -> Vec<Vec<(usize, f32)>> {
+    let ratio = source as f64 / destination as f64;
+    (0..destination)
+        .map(|d| {
+            let (start, end) = (d as f64 * ratio, (d + 1) as f64 * ratio);
+            (start.floor() as usize..(end.ceil() as usize).min(source as usize))
+                .map(|s| {
+                    let covered = end.min(s as f64 + 1.0) - start.max(s as f64);
+                    (s, covered as f32)
+                })
+                .filter(|&(_, covered)| covered > 0.0)
+                .collect()
+        })
+        .collect()
+}
+
+/// Returns the weighted mean of premultiplied colors.
+fn average(pixels: impl Iterator<Item = ([f32; 4], f32)>) -> [f32; 4] {
+    let (sum, weight) = pixels.fold(([0.0; 4], 0.0), |(sum, weight), (pixel, w)| {
+        ([0, 1, 2, 3].map(|c| sum[c] + pixel[c] * w), weight + w)
+    });
+    match weight > 0.0 {
+        true => sum.map(|c| c / weight),
+        false => sum,
+    }
+}
+
+fn premultiply(pixel: &Rgba<u8>) -> [f32; 4] {
+    let alpha = pixel[3] as f32 / 255.0;
+    [
+        pixel[0] as f32 * alpha,
+        pixel[1] as f32 * alpha,
+        pixel[2] as f32 * alpha,
+        alpha,
+    ]
+}
+
+fn unpremultiply([red, green, blue, alpha]: [f32; 4]) -> Rgba<u8> {
+    let channel = |value: f32| match alpha > 0.0 {
+        true => (value / alpha).round().clamp(0.0, 255.0) as u8,
+        false => 0,
+    };
+    Rgba([
+        channel(red),
+        channel(green),
+        channel(blue),
+        (alpha * 255.0).round() as u8,
+    ])
+}
+
+/// Resizes an image by combining the pixels of the block of the image covered by each resized
+/// pixel. A pixel belongs to the block which covers its top left corner.
+fn per_block(
+    image: &Image,
+    width: u32,
+    height: u32,
+    combine: fn(&[Rgba<u8>]) -> Rgba<u8>,
+) -> Image {
+    let mut pixels = Vec::new();
+    Image::from_fn(width, height, |x, y| {
+        pixels.clear();
+        for source_y in block(image.height(), height, y) {
+            for source_x in block(image.width(), width, x) {
+                pixels.push(*image.get_pixel(source_x, source_y));
+            }
+        }
+        combine(&pixels)
+    })
+}
+
+/// Returns the range of source pixels covered by a pixel of a resized axis, which has at least
+/// one pixel.
+fn block(source: u32, destination: u32, d: u32) -> Range<u32> {
+    let start = (d as u64 * source as u64 / destination as u64) as u32;
+    let end = ((d as u64 + 1) * source as u64 / destination as u64) as u32;
+    start..end.max(start + 1).min(source)
+}
+
+/// Returns the median of each channel, the lower one for an even number of pixels.
+fn median(pixels: &[Rgba<u8>]) -> Rgba<u8> {
+    Rgba([0, 1, 2, 3].map(|c| {
+        let mut values = pixels.iter().map(|pixel| pixel[c]).collect::<Vec<_>>();
+        let middle = (values.len() - 1) / 2;
+        *values.select_nth_unstable(middle).1
+    }))
+}
+
+/// Returns the most frequent color, the first one in case of a tie.
+fn mode(pixels: &[Rgba<u8>]) -> Rgba<u8> {
+    let mut counts = HashMap::new();
+    for (index, pixel) in pixels.iter().enumerate() {
+        counts.entry(pixel).or_insert((0, index)).0 += 1;
+    }
+    counts
+        .into_iter()
+        .max_by_key(|&(_, (count, first))| (count, usize::MAX - first))
+        .map_or(Rgba([0, 0, 0, 0]), |(&pixel, _)| pixel)
+}
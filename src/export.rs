@@ -0,0 +1,436 @@
+use std::{
+    fmt::Write as _,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+
+use crate::{args::Export, hicolor::PaletteSchedule, sprites::SpriteData, vram::TileData};
+
+/// Writes the VRAM data of an image next to the output image, in the given format.
+///
+/// The files are named after `output`, with their extension replaced:
+///
+/// - `Export::Raw` writes the tiles to `.2bpp`, the tile map to `.tilemap`, the attribute map to
+///   `.attrmap`, the palettes as little-endian BGR555 words to `.pal`, and the palette write
+///   schedule, if any, to `.schedule`.
+/// - `Export::Gbdk` writes a `.h` header and a `.c` source defining one array for each of them.
+/// - `Export::Rgbds` writes the raw binaries and an `.asm` file next to them, which `INCBIN`s them
+///   by file name under exported labels, along with the map size and tile count.
+///
+/// # Arguments
+///
+/// - `tile_data` - A reference to the VRAM data to be written.
+/// - `schedule` - The palette write schedule of a hicolor image, whose `.pal` holds the palettes
+///   loaded before the frame.
+/// - `output` - The path to the output image.
+/// - `format` - The format of the files.
+///
+/// # Returns
+///
+/// A `Result` with the paths of the written files, or an error if a file couldn't be written.
+pub fn export_tile_data(
+    tile_data: &TileData,
+    schedule: Option<&PaletteSchedule>,
+    output: &str,
+    format: Export,
+) -> Result<Vec<PathBuf>> {
+    let output = Path::new(output);
+    let name = to_identifier(output);
+
+    let files = match format {
+        Export::Raw => raw_files(tile_data, schedule, output),
+        Export::Gbdk => {
+            let header = output.with_extension("h");
+            let header_name = header.file_name().unwrap_or_default().to_string_lossy();
+            vec![
+                (
+                    output.with_extension("c"),
+                    gbdk_source(tile_data, schedule, &name, &header_name)?.into(),
+                ),
+                (header, gbdk_header(tile_data, schedule, &name)?.into()),
+            ]
+        }
+        Export::Rgbds => {
+            let mut files = raw_files(tile_data, schedule, output);
+            let asm = rgbds_source(tile_data, schedule, &name, &files)?;
+            files.push((output.with_extension("asm"), asm.into()));
+            files
+        }
+    };
+    write_files(files)
+}
+
+/// Writes the VRAM and OAM data of sprites next to the output image, in the given format.
+///
+/// The files are named after `output`, with their extension replaced:
+///
+/// - `Export::Raw` writes the tiles to `.2bpp`, the OAM entries to `.oam` and the palettes as
+///   little-endian BGR555 words to `.pal`.
+/// - `Export::Gbdk` writes a `.h` header and a `.c` source defining one array for each of them.
+/// - `Export::Rgbds` writes the raw binaries and an `.asm` file next to them, which `INCBIN`s them
+///   by file name under exported labels, along with the sprite size and the sprite and tile
+///   counts.
+///
+/// # Arguments
+///
+/// - `sprite_data` - A reference to the VRAM and OAM data to be written.
+/// - `output` - The path to the output image.
+/// - `format` - The format of the files.
+///
+/// # Returns
+///
+/// A `Result` with the paths of the written files, or an error if a file couldn't be written.
+pub fn export_sprite_data(
+    sprite_data: &SpriteData,
+    output: &str,
+    format: Export,
+) -> Result<Vec<PathBuf>> {
+    let output = Path::new(output);
+    let name = to_identifier(output);
+
+    let files = match format {
+        Export::Raw => raw_sprite_files(sprite_data, output),
+        Export::Gbdk => {
+            let header = output.with_extension("h");
+            let header_name = header.file_name().unwrap_or_default().to_string_lossy();
+            vec![
+                (
+                    output.with_extension("c"),
+                    gbdk_sprite_source(sprite_data, &name, &header_name)?.into(),
+                ),
+                (header, gbdk_sprite_header(sprite_data, &name)?.into()),
+            ]
+        }
+        Export::Rgbds => {
+            let mut files = raw_sprite_files(sprite_data, output);
+            let asm = rgbds_sprite_source(sprite_data, &name, &files)?;
+            files.push((output.with_extension("asm"), asm.into()));
+            files
+        }
+    };
+    write_files(files)
+}
+
+/// Writes files, and returns their paths.
+fn write_files(files: Vec<(PathBuf, Vec<u8>)>) -> Result<Vec<PathBuf>> {
+    files
+        .into_iter()
+        .map(|(path, contents)| {
+            fs::write(&path, contents)?;
+            Ok(path)
+        })
+        .collect()
+}
+
+/// Returns the raw binary files of the VRAM data and the palette write schedule, with their paths.
+fn raw_files(
+    tile_data: &TileData,
+    schedule: Option<&PaletteSchedule>,
+    output: &Path,
+) -> Vec<(PathBuf, Vec<u8>)> {
+    let mut files = vec![
+        (output.with_extension("2bpp"), tile_data.tiles.concat()),
+        (output.with_extension("tilemap"), tile_data.tile_map.clone()),
+        (
+            output.with_extension("attrmap"),
+            tile_data.attribute_map.clone(),
+        ),
+        (
+            output.with_extension("pal"),
+            tile_data
+                .palettes
+                .iter()
+                .flat_map(|word| word.to_le_bytes())
+                .collect(),
+        ),
+    ];
+    if let Some(schedule) = schedule {
+        files.push((output.with_extension("schedule"), schedule.to_bytes()));
+    }
+    files
+}
+
+fn gbdk_header(
+    tile_data: &TileData,
+    schedule: Option<&PaletteSchedule>,
+    name: &str,
+) -> Result<String> {
+    let guard = name.to_uppercase();
+    let mut header = String::new();
+    writeln!(header, "// Generated by gbc-image-transform\n")?;
+    writeln!(header, "#ifndef {guard}_H\n#define {guard}_H\n")?;
+    writeln!(header, "#include <stdint.h>\n")?;
+    writeln!(header, "#define {name}_WIDTH {}", tile_data.columns)?;
+    writeln!(header, "#define {name}_HEIGHT {}", tile_data.rows)?;
+    writeln!(
+        header,
+        "#define {name}_TILE_COUNT {}\n",
+        tile_data.tiles.len()
+    )?;
+    writeln!(header, "extern const uint8_t {name}_tiles[];")?;
+    writeln!(header, "extern const uint8_t {name}_map[];")?;
+    writeln!(header, "extern const uint8_t {name}_attributes[];")?;
+    writeln!(header, "extern const uint16_t {name}_palettes[];\n")?;
+    if let Some(schedule) = schedule {
+        writeln!(
+            header,
+            "#define {name}_WRITES_PER_LINE {}",
+            schedule.writes_per_line
+        )?;
+        writeln!(header, "extern const uint8_t {name}_schedule[];\n")?;
+    }
+    writeln!(header, "#endif")?;
+    Ok(header)
+}
+
+fn gbdk_source(
+    tile_data: &TileData,
+    schedule: Option<&PaletteSchedule>,
+    name: &str,
+    header_name: &str,
+) -> Result<String> {
+    let TileData {
+        tiles,
+        tile_map,
+        attribute_map,
+        palettes,
+        columns,
+        ..
+    } = tile_data;
+
+    let mut source = String::new();
+    writeln!(source, "// Generated by gbc-image-transform\n")?;
+    writeln!(source, "#include \"{header_name}\"\n")?;
+    write_c_array(
+        &mut source,
+        "uint8_t",
+        &format!("{name}_tiles"),
+        &tiles.concat(),
+        16,
+    )?;
+    write_c_array(
+        &mut source,
+        "uint8_t",
+        &format!("{name}_map"),
+        tile_map,
+        *columns as usize,
+    )?;
+    write_c_array(
+        &mut source,
+        "uint8_t",
+        &format!("{name}_attributes"),
+        attribute_map,
+        *columns as usize,
+    )?;
+    write_c_array(
+        &mut source,
+        "uint16_t",
+        &format!("{name}_palettes"),
+        palettes,
+        4,
+    )?;
+    if let Some(schedule) = schedule {
+        write_c_array(
+            &mut source,
+            "uint8_t",
+            &format!("{name}_schedule"),
+            &schedule.to_bytes(),
+            3 * schedule.writes_per_line,
+        )?;
+    }
+    Ok(source)
+}
+
+fn rgbds_source(
+    tile_data: &TileData,
+    schedule: Option<&PaletteSchedule>,
+    name: &str,
+    files: &[(PathBuf, Vec<u8>)],
+) -> Result<String> {
+    let mut source = String::new();
+    writeln!(source, "; Generated by gbc-image-transform\n")?;
+    writeln!(source, "DEF {name}_WIDTH EQU {}", tile_data.columns)?;
+    writeln!(source, "DEF {name}_HEIGHT EQU {}", tile_data.rows)?;
+    writeln!(
+        source,
+        "DEF {name}_TILE_COUNT EQU {}\n",
+        tile_data.tiles.len()
+    )?;
+    if let Some(schedule) = schedule {
+        writeln!(
+            source,
+            "DEF {name}_WRITES_PER_LINE EQU {}\n",
+            schedule.writes_per_line
+        )?;
+    }
+    writeln!(source, "SECTION \"{name}\", ROMX\n")?;
+    write_incbins(
+        &mut source,
+        name,
+        files,
+        &["tiles", "map", "attributes", "palettes", "schedule"],
+    )?;
+    Ok(source)
+}
+
+/// Returns the raw binary files of the sprite data, with their paths.
+fn raw_sprite_files(sprite_data: &SpriteData, output: &Path) -> Vec<(PathBuf, Vec<u8>)> {
+    vec![
+        (output.with_extension("2bpp"), sprite_data.tiles.concat()),
+        (output.with_extension("oam"), sprite_data.oam.concat()),
+        (
+            output.with_extension("pal"),
+            sprite_data
+                .palettes
+                .iter()
+                .flat_map(|word| word.to_le_bytes())
+                .collect(),
+        ),
+    ]
+}
+
+fn gbdk_sprite_header(sprite_data: &SpriteData, name: &str) -> Result<String> {
+    let guard = name.to_uppercase();
+    let mut header = String::new();
+    writeln!(header, "// Generated by gbc-image-transform\n")?;
+    writeln!(header, "#ifndef {guard}_H\n#define {guard}_H\n")?;
+    writeln!(header, "#include <stdint.h>\n")?;
+    writeln!(
+        header,
+        "#define {name}_SPRITE_HEIGHT {}",
+        sprite_data.sprite_height
+    )?;
+    writeln!(
+        header,
+        "#define {name}_SPRITE_COUNT {}",
+        sprite_data.oam.len()
+    )?;
+    writeln!(
+        header,
+        "#define {name}_TILE_COUNT {}\n",
+        sprite_data.tiles.len()
+    )?;
+    writeln!(header, "extern const uint8_t {name}_tiles[];")?;
+    writeln!(header, "extern const uint8_t {name}_oam[];")?;
+    writeln!(header, "extern const uint16_t {name}_palettes[];\n")?;
+    writeln!(header, "#endif")?;
+    Ok(header)
+}
+
+fn gbdk_sprite_source(sprite_data: &SpriteData, name: &str, header_name: &str) -> Result<String> {
+    let SpriteData {
+        tiles,
+        oam,
+        palettes,
+        ..
+    } = sprite_data;
+
+    let mut source = String::new();
+    writeln!(source, "// Generated by gbc-image-transform\n")?;
+    writeln!(source, "#include \"{header_name}\"\n")?;
+    write_c_array(
+        &mut source,
+        "uint8_t",
+        &format!("{name}_tiles"),
+        &tiles.concat(),
+        16,
+    )?;
+    write_c_array(
+        &mut source,
+        "uint8_t",
+        &format!("{name}_oam"),
+        &oam.concat(),
+        4,
+    )?;
+    write_c_array(
+        &mut source,
+        "uint16_t",
+        &format!("{name}_palettes"),
+        palettes,
+        4,
+    )?;
+    Ok(source)
+}
+
+fn rgbds_sprite_source(
+    sprite_data: &SpriteData,
+    name: &str,
+    files: &[(PathBuf, Vec<u8>)],
+) -> Result<String> {
+    let mut source = String::new();
+    writeln!(source, "; Generated by gbc-image-transform\n")?;
+    writeln!(
+        source,
+        "DEF {name}_SPRITE_HEIGHT EQU {}",
+        sprite_data.sprite_height
+    )?;
+    writeln!(
+        source,
+        "DEF {name}_SPRITE_COUNT EQU {}",
+        sprite_data.oam.len()
+    )?;
+    writeln!(
+        source,
+        "DEF {name}_TILE_COUNT EQU {}\n",
+        sprite_data.tiles.len()
+    )?;
+    writeln!(source, "SECTION \"{name}\", ROMX\n")?;
+    write_incbins(&mut source, name, files, &["tiles", "oam", "palettes"])?;
+    Ok(source)
+}
+
+/// Writes an exported label for each file, followed by an `INCBIN` of the file and an end label.
+/// The files are included by file name, since they sit next to the `.asm` file, so rgbasm finds
+/// them when it runs from that directory or has it as an include path with `-I`.
+fn write_incbins(
+    source: &mut String,
+    name: &str,
+    files: &[(PathBuf, Vec<u8>)],
+    labels: &[&str],
+) -> Result<()> {
+    for ((path, _), label) in files.iter().zip(labels) {
+        writeln!(source, "{name}_{label}::")?;
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        writeln!(source, "    INCBIN \"{file_name}\"")?;
+        writeln!(source, "{name}_{label}_end::\n")?;
+    }
+    Ok(())
+}
+
+/// Writes a C array definition with the given element type and name, `per_line` elements per line.
+fn write_c_array<T: Into<u32> + Copy>(
+    source: &mut String,
+    element_type: &str,
+    name: &str,
+    values: &[T],
+    per_line: usize,
+) -> Result<()> {
+    let width = std::mem::size_of::<T>() * 2;
+    writeln!(source, "const {element_type} {name}[{}] = {{", values.len())?;
+    for line in values.chunks(per_line.max(1)) {
+        let line = line
+            .iter()
+            .map(|&value| format!("0x{:0width$X}", value.into()))
+            .collect::<Vec<_>>()
+            .join(", ");
+        writeln!(source, "    {line},")?;
+    }
+    writeln!(source, "}};\n")?;
+    Ok(())
+}
+
+/// Derives a C and RGBDS identifier from the file name of a path, replacing invalid characters
+/// with underscores.
+fn to_identifier(path: &Path) -> String {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let identifier = stem
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect::<String>();
+    match identifier.chars().next() {
+        Some(c) if !c.is_ascii_digit() => identifier,
+        _ => format!("_{identifier}"),
+    }
+}
@@ -0,0 +1,302 @@
+use image::{imageops::crop_imm, Rgb};
+
+use crate::{
+    args::Metric,
+    cluster::{color_histogram, KmeansOptions},
+    quantize::{quantize_palette, Quantizer},
+    tiles::{
+        compute_tile_error, find_best_palette, find_tile_palettes, get_tile_colors, TileOptions,
+        TilePalettes, COLORS_PER_PALETTE, MAX_ROUNDS, NUM_PALETTES, TILE_SIZE,
+    },
+    vram::to_bgr555,
+    Image,
+};
+
+/// Number of palettes in each of the two banks of the palette memory, which bands use in turn.
+pub const PALETTES_PER_BANK: usize = NUM_PALETTES / 2;
+
+/// Value of an entry of the encoded schedule which doesn't write anything.
+const NO_WRITE: u8 = 0xFF;
+
+/// Colors to be written to the palette memory during the horizontal blanking period after each
+/// scanline.
+#[derive(Debug, Clone)]
+pub struct PaletteSchedule {
+    /// Maximum number of colors written after a scanline.
+    pub writes_per_line: usize,
+    /// For each scanline of the image, the colors written after it: the byte address of the color
+    /// in the palette memory, i.e. `(palette * COLORS_PER_PALETTE + color) * 2` as written to
+    /// `BCPS`, and its BGR555 word.
+    pub lines: Vec<Vec<(u8, u16)>>,
+}
+
+impl PaletteSchedule {
+    /// Encodes the schedule as `writes_per_line` entries of 3 bytes for each scanline: the byte
+    /// address to write to `BCPS`, or `0xFF` for no write, followed by the little-endian BGR555
+    /// word. The address doesn't have the auto-increment bit 7 set, so a consumer either sets it
+    /// and writes both bytes to `BCPD`, or writes `BCPS` again before the high byte.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.lines
+            .iter()
+            .flat_map(|writes| {
+                (0..self.writes_per_line).flat_map(|write| match writes.get(write) {
+                    Some(&(index, word)) => {
+                        let [low, high] = word.to_le_bytes();
+                        [index, low, high]
+                    }
+                    None => [NO_WRITE, 0, 0],
+                })
+            })
+            .collect()
+    }
+
+    /// Returns the number of colors written during the frame.
+    pub fn write_count(&self) -> usize {
+        self.lines.iter().map(Vec::len).sum()
+    }
+}
+
+/// Background palettes which change from one band of tile rows to the next, and the schedule of
+/// palette writes which changes them.
+#[derive(Debug, Clone)]
+pub struct Hicolor {
+    /// The palettes of each band, `PALETTES_PER_BANK` per band, and the palette assigned to each
+    /// tile.
+    pub tile_palettes: TilePalettes,
+    /// The palette writes after the first two bands, whose palettes are loaded before the frame.
+    pub schedule: PaletteSchedule,
+}
+
+/// Finds background palettes for each band of tile rows of an image, so that it can be displayed
+/// with more colors by rewriting palettes while the screen is drawn.
+///
+/// # Arguments
+///
+/// - `image` - A reference to the downscaled image to be split into tiles, one pixel per logical
+///   pixel.
+/// - `options` - How transparent pixels are handled. The tiles are 8x8, and
+///   `PALETTES_PER_BANK` palettes are found for each band.
+/// - `band_rows` - The number of tile rows in each band.
+/// - `writes_per_line` - The number of colors which can be written after each scanline.
+/// - `metric` - The color distance metric used to measure errors.
+/// - `quantizer` - The color quantization algorithm used to find the palettes.
+/// - `kmeans` - The parameters of the k-means clustering of tiles.
+///
+/// # Algorithm
+///
+/// The 8 palettes are split into two banks of 4, which bands use in turn, so that the palettes of
+/// the next band can be written to one bank while the current band is drawn with the other. The
+/// palettes of the first two bands are loaded before the frame. For each following band, palettes
+/// are found like for a whole background, from the tiles of the band. If writing them all would
+/// exceed `writes_per_line` colors on any scanline of the previous band, only as many palettes as
+/// fit are replaced, one at a time, each time with the replacement which reduces the error of the
+/// band the most, and the other palettes are kept from two bands before. Each tile is then
+/// assigned the palette of its band's bank which reproduces it best.
+///
+/// # Returns
+///
+/// The palettes of each band and the palette write schedule.
+pub fn find_hicolor_palettes(
+    image: &Image,
+    options: &TileOptions,
+    band_rows: u32,
+    writes_per_line: usize,
+    metric: Metric,
+    quantizer: &dyn Quantizer,
+    kmeans: &KmeansOptions,
+) -> Hicolor {
+    let options = TileOptions {
+        tile_height: TILE_SIZE,
+        num_palettes: PALETTES_PER_BANK,
+        ..*options
+    };
+    let band_height = band_rows * TILE_SIZE;
+    let columns = image.width().div_ceil(TILE_SIZE);
+    let rows = image.height().div_ceil(TILE_SIZE);
+    let tiles = (0..rows)
+        .flat_map(|row| (0..columns).map(move |column| (column, row)))
+        .map(|(column, row)| {
+            let origin = (column * TILE_SIZE, row * TILE_SIZE);
+            get_tile_colors(image, origin, TILE_SIZE, options.transparent)
+        })
+        .collect::<Vec<_>>();
+    // the number of palettes which can be written while a band is drawn
+    let budget =
+        (writes_per_line * band_height as usize / COLORS_PER_PALETTE).min(PALETTES_PER_BANK);
+
+    let mut palettes = Vec::<Vec<Rgb<u8>>>::new();
+    let mut assignments = vec![0; tiles.len()];
+    let mut written = Vec::new();
+    for (band, top) in (0..image.height())
+        .step_by(band_height as usize)
+        .enumerate()
+    {
+        let height = band_height.min(image.height() - top);
+        let band_image = crop_imm(image, 0, top, image.width(), height).to_image();
+        let candidates =
+            find_tile_palettes(&band_image, &options, metric, quantizer, kmeans).palettes;
+        let first_tile = (top / TILE_SIZE * columns) as usize;
+        let last_tile = ((top + height).div_ceil(TILE_SIZE) * columns) as usize;
+        let band_tiles = &tiles[first_tile..last_tile];
+
+        let bank = match band {
+            0 | 1 => candidates,
+            _ if budget == PALETTES_PER_BANK => {
+                written.extend((0..PALETTES_PER_BANK).map(|slot| (band, slot)));
+                candidates
+            }
+            _ => {
+                let inherited =
+                    palettes[(band - 2) * PALETTES_PER_BANK..][..PALETTES_PER_BANK].to_vec();
+                let (mut bank, replaced) =
+                    replace_palettes(inherited, &candidates, band_tiles, budget, metric);
+                let num_colors = COLORS_PER_PALETTE - options.reserve_transparent as usize;
+                refine_palettes(
+                    &mut bank, &replaced, band_tiles, num_colors, metric, quantizer, kmeans,
+                );
+                written.extend(replaced.into_iter().map(|slot| (band, slot)));
+                bank
+            }
+        };
+        for (colors, assignment) in band_tiles
+            .iter()
+            .zip(&mut assignments[first_tile..last_tile])
+        {
+            *assignment = band * PALETTES_PER_BANK + find_best_palette(&bank, colors, metric);
+        }
+        palettes.extend(bank);
+    }
+
+    let tile_palettes = TilePalettes {
+        palettes,
+        assignments,
+        columns,
+        rows,
+        tile_height: TILE_SIZE,
+        reserve_transparent: options.reserve_transparent,
+    };
+
+    // write the palettes of each band after the scanlines of the previous band, in order
+    let padded_palettes = tile_palettes.padded_palettes();
+    let mut lines = vec![Vec::new(); image.height() as usize];
+    for band in 2..padded_palettes.len().div_ceil(PALETTES_PER_BANK) {
+        let writes = written
+            .iter()
+            .filter(|&&(written_band, _)| written_band == band)
+            .flat_map(|&(_, slot)| {
+                let palette = band * PALETTES_PER_BANK + slot;
+                padded_palettes[palette]
+                    .iter()
+                    .enumerate()
+                    .map(move |(index, color)| {
+                        let index = palette % NUM_PALETTES * COLORS_PER_PALETTE + index;
+                        ((index * 2) as u8, to_bgr555(color))
+                    })
+            })
+            .collect::<Vec<_>>();
+        let first_line = (band - 1) * band_height as usize;
+        for (line, writes) in writes.chunks(writes_per_line).enumerate() {
+            lines[first_line + line] = writes.to_vec();
+        }
+    }
+
+    Hicolor {
+        tile_palettes,
+        schedule: PaletteSchedule {
+            writes_per_line,
+            lines,
+        },
+    }
+}
+
+/// Replaces up to `count` palettes of a bank with new palettes, one at a time, each time choosing
+/// the palette to replace and its replacement which reduce the error of the tiles the most, until
+/// no replacement reduces it.
+///
+/// Returns the new bank, and the indices of the palettes which were replaced, in increasing order.
+fn replace_palettes(
+    mut bank: Vec<Vec<Rgb<u8>>>,
+    candidates: &[Vec<Rgb<u8>>],
+    tiles: &[Vec<Rgb<u8>>],
+    count: usize,
+    metric: Metric,
+) -> (Vec<Vec<Rgb<u8>>>, Vec<usize>) {
+    let error = |bank: &[Vec<Rgb<u8>>]| -> f64 {
+        tiles
+            .iter()
+            .map(|colors| {
+                let best = find_best_palette(bank, colors, metric);
+                compute_tile_error(&bank[best], colors, metric)
+            })
+            .sum()
+    };
+
+    let mut replaced = Vec::new();
+    let mut current = error(&bank);
+    for _ in 0..count {
+        let best = (0..bank.len())
+            .filter(|slot| !replaced.contains(slot))
+            .flat_map(|slot| {
+                candidates
+                    .iter()
+                    .filter(|candidate| !candidate.is_empty())
+                    .map(move |candidate| (slot, candidate))
+            })
+            .map(|(slot, candidate)| {
+                let mut trial = bank.clone();
+                trial[slot] = candidate.clone();
+                let error = error(&trial);
+                (slot, trial, error)
+            })
+            .min_by(|(_, _, a), (_, _, b)| a.total_cmp(b));
+        match best {
+            Some((slot, trial, error)) if error < current => {
+                bank = trial;
+                current = error;
+                replaced.push(slot);
+            }
+            _ => break,
+        }
+    }
+    replaced.sort_unstable();
+    (bank, replaced)
+}
+
+/// Refines the replaced palettes of a bank, which were found for all the tiles of the band, to
+/// the tiles the kept palettes don't reproduce well: until the assignment is stable or
+/// `MAX_ROUNDS` is reached, every tile is assigned its best palette, and each replaced palette is
+/// computed again from the colors of its tiles. A replaced palette left without tiles is kept.
+fn refine_palettes(
+    bank: &mut [Vec<Rgb<u8>>],
+    replaced: &[usize],
+    tiles: &[Vec<Rgb<u8>>],
+    num_colors: usize,
+    metric: Metric,
+    quantizer: &dyn Quantizer,
+    kmeans: &KmeansOptions,
+) {
+    let mut assignments = Vec::new();
+    for _ in 0..MAX_ROUNDS {
+        let previous = assignments;
+        assignments = tiles
+            .iter()
+            .map(|colors| find_best_palette(bank, colors, metric))
+            .collect::<Vec<_>>();
+        if assignments == previous {
+            break;
+        }
+        for &slot in replaced {
+            let histogram = color_histogram(
+                tiles
+                    .iter()
+                    .zip(&assignments)
+                    .filter(|(_, &assignment)| assignment == slot)
+                    .flat_map(|(colors, _)| colors.iter().copied()),
+            );
+            let palette = quantize_palette(quantizer, &histogram, num_colors, kmeans);
+            if !palette.is_empty() {
+                bank[slot] = palette;
+            }
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use std::{fs::File, io::BufWriter};
+
+use anyhow::{bail, Result};
+use image::{GrayImage, Luma, Rgb, Rgba};
+use png::{BitDepth, ColorType, Encoder};
+
+use crate::{upscale, Image};
+
+/// Maximum number of colors in the palette of an indexed PNG.
+const MAX_COLORS: usize = 256;
+
+/// An image whose pixels are indices into a palette of RGBA colors.
+pub struct IndexedImage {
+    /// The palette index of each pixel.
+    pub indices: GrayImage,
+    /// The colors of the palette, followed by the colors of pixels which aren't opaque or aren't
+    /// in the palette.
+    pub palette: Vec<Rgba<u8>>,
+}
+
+impl IndexedImage {
+    /// Indexes the colors of an image whose colors were reduced to the given palettes.
+    ///
+    /// # Arguments
+    ///
+    /// - `image` - A reference to the image with reduced colors.
+    /// - `palettes` - The palettes, whose colors make up the palette of the indexed image one after
+    ///   the other, in the same order, so that the indices match those of the Game Boy Color.
+    /// - `palette_at` - Returns the index in `palettes` of the palette used at the given pixel.
+    /// - `reserve_transparent` - Whether color 0 of each palette is reserved for transparent
+    ///   pixels, in which case it is transparent in the indexed image.
+    ///
+    /// # Algorithm
+    ///
+    /// If color 0 is reserved, a fully transparent pixel takes the index of color 0 of the palette
+    /// used at its position. An opaque pixel takes the index of its color in that palette, or of
+    /// the first entry with the same color if it isn't there. Other pixels take the index of a new
+    /// entry with their color and alpha, which is shared by all pixels with the same color and
+    /// alpha. Fully transparent pixels all share a single transparent black entry, since their
+    /// color can't be seen.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` with the indexed image, or an error if it would need more than 256 colors.
+    pub fn new(
+        image: &Image,
+        palettes: &[Vec<Rgb<u8>>],
+        palette_at: impl Fn(u32, u32) -> usize,
+        reserve_transparent: bool,
+    ) -> Result<Self> {
+        let first_color = reserve_transparent as usize;
+        let mut palette = palettes
+            .iter()
+            .flat_map(|colors| {
+                colors.iter().enumerate().map(|(index, color)| {
+                    let alpha = match index < first_color {
+                        true => 0,
+                        false => 255,
+                    };
+                    Rgba([color[0], color[1], color[2], alpha])
+                })
+            })
+            .collect::<Vec<_>>();
+        let offsets = palettes
+            .iter()
+            .scan(0, |offset, colors| {
+                *offset += colors.len();
+                Some(*offset - colors.len())
+            })
+            .collect::<Vec<_>>();
+
+        let mut indices = GrayImage::new(image.width(), image.height());
+        for (x, y, &pixel) in image.enumerate_pixels() {
+            let pixel = match pixel[3] {
+                0 => Rgba([0, 0, 0, 0]),
+                _ => pixel,
+            };
+            let assigned = palette_at(x, y);
+            let index = match (reserve_transparent, pixel[3]) {
+                (true, 0) => Some(offsets[assigned]),
+                _ => palettes[assigned]
+                    .iter()
+                    .enumerate()
+                    .skip(first_color)
+                    .find(|(_, color)| pixel[3] == 255 && color.0 == [pixel[0], pixel[1], pixel[2]])
+                    .map(|(index, _)| offsets[assigned] + index)
+                    .or_else(|| palette.iter().position(|&color| color == pixel)),
+            };
+            let index = match index {
+                Some(index) => index,
+                None => {
+                    palette.push(pixel);
+                    palette.len() - 1
+                }
+            };
+            if index >= MAX_COLORS {
+                bail!("an indexed PNG has at most {MAX_COLORS} colors, but the image needs more");
+            }
+            indices.put_pixel(x, y, Luma([index as u8]));
+        }
+
+        Ok(IndexedImage { indices, palette })
+    }
+
+    /// Upscales the image like `upscale`, each logical pixel becoming a block of `block` x `block`
+    /// pixels.
+    pub fn upscale(&mut self, width: u32, height: u32, block: u32) {
+        self.indices = upscale(&self.indices, width, height, block);
+    }
+
+    /// Saves the image as an indexed PNG, with a `PLTE` chunk, a `tRNS` chunk if some colors aren't
+    /// opaque, and the smallest bit depth of 1, 2, 4 or 8 bits per pixel which fits the palette.
+    pub fn save(&self, path: &str) -> Result<()> {
+        let depth = match self.palette.len() {
+            0..=2 => BitDepth::One,
+            3..=4 => BitDepth::Two,
+            5..=16 => BitDepth::Four,
+            _ => BitDepth::Eight,
+        };
+        let bits = depth as usize;
+
+        let (width, height) = self.indices.dimensions();
+        let mut encoder = Encoder::new(BufWriter::new(File::create(path)?), width, height);
+        encoder.set_color(ColorType::Indexed);
+        encoder.set_depth(depth);
+        encoder.set_palette(
+            self.palette
+                .iter()
+                .flat_map(|color| [color[0], color[1], color[2]])
+                .collect::<Vec<_>>(),
+        );
+        // the alpha of the entries after the last transparent one defaults to opaque
+        if let Some(last) = self.palette.iter().rposition(|color| color[3] < 255) {
+            encoder.set_trns(
+                self.palette[..=last]
+                    .iter()
+                    .map(|color| color[3])
+                    .collect::<Vec<_>>(),
+            );
+        }
+
+        // pack the pixels of each row, the leftmost one in the high bits of each byte
+        let row_bytes = (width as usize * bits).div_ceil(8);
+        let mut data = vec![0; row_bytes * height as usize];
+        for (x, y, index) in self.indices.enumerate_pixels() {
+            let bit = x as usize * bits;
+            let shift = 8 - bits - bit % 8;
+            data[y as usize * row_bytes + bit / 8] |= index[0] << shift;
+        }
+
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&data)?;
+        writer.finish()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,74 @@
+use image::Rgb;
+
+use crate::{
+    args::Metric,
+    vram::{from_5bit, to_5bit},
+};
+
+/// Number of bits kept from each channel to index the table, as in 15-bit color.
+const BITS_PER_CHANNEL: u32 = 5;
+
+/// A lookup table mapping each 15-bit color to the closest color of a palette, so that an image
+/// can be mapped to the palette with a single lookup per pixel.
+#[derive(Debug, Clone)]
+pub struct ColorLut<'a> {
+    palette: &'a [Rgb<u8>],
+    /// Index into `palette` for each 15-bit color, red in the low bits as in BGR555.
+    indices: Vec<u16>,
+}
+
+impl<'a> ColorLut<'a> {
+    /// Builds the lookup table of a palette.
+    ///
+    /// # Arguments
+    ///
+    /// - `palette` - A slice of `Rgb<u8>` color values to choose from.
+    /// - `metric` - The color distance metric.
+    ///
+    /// # Algorithm
+    ///
+    /// Each of the 32,768 15-bit colors is compared to each palette color with the given metric.
+    /// This costs as much as mapping a 181x181 image directly, whatever the size of the image.
+    pub fn new(palette: &'a [Rgb<u8>], metric: Metric) -> Self {
+        let prepared_palette = palette
+            .iter()
+            .map(|color| metric.prepare(color))
+            .collect::<Vec<_>>();
+
+        let indices = (0..1 << (3 * BITS_PER_CHANNEL))
+            .map(|key| {
+                let prepared_color = metric.prepare(&from_key(key));
+                prepared_palette
+                    .iter()
+                    .map(|color| metric.compute_prepared_distance(color, &prepared_color))
+                    .enumerate()
+                    .min_by(|(_, a), (_, b)| a.total_cmp(b))
+                    .map_or(0, |(index, _)| index as u16)
+            })
+            .collect();
+
+        ColorLut { palette, indices }
+    }
+
+    /// Returns the palette color closest to `color` rounded to 15-bit, or black
+    /// (`Rgb([0, 0, 0])`) if the palette is empty.
+    pub fn closest_color(&self, color: &Rgb<u8>) -> Rgb<u8> {
+        self.palette
+            .get(self.indices[to_key(color)] as usize)
+            .copied()
+            .unwrap_or(Rgb([0, 0, 0]))
+    }
+}
+
+/// Returns the index of the 15-bit color closest to `color` in the table.
+fn to_key(color: &Rgb<u8>) -> usize {
+    let [red, green, blue] = color.0.map(|channel| to_5bit(channel as f32) as usize);
+    red | green << BITS_PER_CHANNEL | blue << (2 * BITS_PER_CHANNEL)
+}
+
+/// Returns the 15-bit color at an index of the table, expanded to 8 bits per channel like the
+/// palette colors, so that a pixel which is already a palette color is mapped to itself.
+fn from_key(key: usize) -> Rgb<u8> {
+    let mask = (1 << BITS_PER_CHANNEL) - 1;
+    Rgb([0, 1, 2].map(|i| from_5bit((key >> (i * BITS_PER_CHANNEL) & mask) as u8)))
+}
@@ -1,14 +1,47 @@
 mod args;
-use crate::args::Args;
+mod cluster;
+mod distance;
+mod dither;
+mod dmg;
+mod downsample;
+mod export;
+mod hicolor;
+mod indexed;
+mod lut;
+mod overlay;
+mod palette_file;
+mod quantize;
+mod screen;
+mod sprites;
+mod tiles;
+mod vram;
+use crate::{
+    args::{Args, Edge, Filter, Metric, Mode, Quantization, Seed, Space},
+    cluster::{color_histogram, lock_palette, KmeansOptions},
+    dither::{dither_colors, DitherOptions},
+    dmg::{apply_ramp, find_shades, to_grayscale},
+    downsample::downsample,
+    export::{export_sprite_data, export_tile_data},
+    hicolor::find_hicolor_palettes,
+    indexed::IndexedImage,
+    lut::ColorLut,
+    overlay::find_overlay,
+    palette_file::{load_palette, save_palette},
+    quantize::{quantize_palette, Quantizer},
+    screen::get_screen_image,
+    sprites::build_sprite_data,
+    tiles::{find_tile_palettes, reduce_tile_colors, TileOptions, NUM_PALETTES, TILE_SIZE},
+    vram::build_tile_data,
+};
+
+use std::{collections::HashSet, path::Path, slice};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use clap::Parser;
 use image::{
-    imageops::{resize, FilterType},
+    imageops::{crop_imm, replace},
     ImageBuffer, Pixel, Rgb, Rgba,
 };
-use kmeans_colors::get_kmeans;
-use palette::{cast::ComponentsAs, FromColor, Srgb, Srgba};
 use tracing::info;
 use tracing_subscriber::FmtSubscriber;
 
@@ -19,54 +52,577 @@ fn main() -> Result<()> {
         input,
         output,
         pixelation_factor,
+        edge,
+        filter,
         num_colors,
+        palette,
+        lock_colors,
+        palette_out,
         transparent,
+        alpha_threshold,
+        matte,
+        reserve_transparent,
+        mode,
+        sprite_size,
+        band_rows,
+        palette_writes,
+        shades,
+        preset,
+        ramp,
+        overlay,
+        resize: strategy,
+        size,
+        anchor,
+        letterbox,
+        native,
+        scale,
+        indexed,
+        export,
+        merge_threshold,
+        quantizer,
+        metric,
+        space,
+        dither,
+        serpentine,
+        dither_strength,
+        dither_spread,
+        seed,
+        max_iterations,
+        converge,
+        runs,
+        snap_centroids,
     } = Args::parse();
 
+    if export.is_some() && matches!(mode, Mode::Free | Mode::Dmg) {
+        bail!("exporting tile data requires `--mode bg`, `--mode hicolor` or `--mode obj`");
+    }
+    if overlay && mode != Mode::Background {
+        bail!("a sprite overlay requires `--mode bg`");
+    }
+    if indexed && !output.to_lowercase().ends_with(".png") {
+        bail!("indexed output requires a `.png` output path");
+    }
+    if indexed && mode == Mode::Hicolor {
+        bail!("indexed output isn't supported with `--mode hicolor`, which has too many colors");
+    }
+    if reserve_transparent && mode == Mode::Dmg {
+        bail!("reserving a transparent color isn't supported with `--mode dmg`");
+    }
+    if palette.is_some() && mode != Mode::Free {
+        bail!("using a palette file requires `--mode free`");
+    }
+    if lock_colors.is_some() && quantizer != Quantization::Kmeans {
+        bail!("locking palette colors requires `--quantizer kmeans`");
+    }
+
     let subscriber = FmtSubscriber::builder().finish();
     tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
 
+    let kmeans = KmeansOptions {
+        space: space.unwrap_or(Space::from(metric)),
+        seed: match seed {
+            Seed::Fixed(seed) => seed,
+            Seed::Random => rand::random(),
+        },
+        max_iterations,
+        converge,
+        runs,
+        snap: snap_centroids,
+    };
+    info!("using k-means seed {}", kmeans.seed);
+    let quantizer = quantizer.quantizer(kmeans);
+
+    let (fixed_palette, locked_colors) = match palette {
+        Some(path) => {
+            info!("loading palette from {}", path);
+            let colors = load_palette(&path)?;
+            info!("loaded {} unique colors", colors.len());
+            match lock_colors {
+                Some(count) if count > colors.len() => bail!(
+                    "cannot lock {} colors, the palette only has {}",
+                    count,
+                    colors.len()
+                ),
+                Some(count) => (None, colors[..count].to_vec()),
+                None => (Some(colors), Vec::new()),
+            }
+        }
+        None => (None, Vec::new()),
+    };
+
     info!("loading image from {}", input);
-    let mut image = get_pixelated_image(&input, pixelation_factor)?;
-    info!("finding palette");
-    let palette = find_palette(&image, num_colors, transparent)?;
-    info!("reducing colors");
-    reduce_colors(&mut image, &palette);
-    info!("saving image to {}", output);
-    image.save(output)?;
+    let (mut image, (width, height)) = match strategy {
+        Some(strategy) => {
+            let image = get_screen_image(&input, size, strategy, anchor, letterbox, filter)?;
+            let dimensions = image.dimensions();
+            (image, dimensions)
+        }
+        None => {
+            let (image, dimensions) = get_pixelated_image(&input, pixelation_factor, edge, filter)?;
+            match native {
+                true => {
+                    let dimensions = image.dimensions();
+                    (image, dimensions)
+                }
+                false => (image, dimensions),
+            }
+        }
+    };
+    flatten_alpha(&mut image, alpha_threshold, matte);
+    let dither = dither.map(|dither| DitherOptions {
+        dither,
+        serpentine,
+        strength: dither_strength,
+        spread: dither_spread,
+    });
+    // the size of a logical pixel in the output image
+    let block = match (strategy, native) {
+        (None, false) => pixelation_factor * scale,
+        _ => scale,
+    };
+    let (width, height) = (width * scale, height * scale);
+    let indexed_image = match mode {
+        Mode::Free => {
+            let palette = match fixed_palette {
+                Some(palette) => palette,
+                None => {
+                    info!("finding palette");
+                    let palette = find_palette(
+                        &image,
+                        num_colors.saturating_sub(reserve_transparent as usize),
+                        transparent,
+                        &*quantizer,
+                        &locked_colors,
+                        &kmeans,
+                    )?;
+                    info!("found {} unique colors", palette.len());
+                    palette
+                }
+            };
+            // the palette as saved, with black for the reserved transparent color
+            let mut slots = match reserve_transparent {
+                true => vec![Rgb([0, 0, 0])],
+                false => Vec::new(),
+            };
+            slots.extend(&palette);
+            if let Some(path) = &palette_out {
+                save_palette(path, slice::from_ref(&slots))?;
+                info!("saved palette to {}", path);
+            }
+            match dither {
+                Some(dither) => {
+                    info!("dithering colors");
+                    dither_colors(&mut image, &dither, metric, |_, _| &palette);
+                }
+                None => {
+                    info!("reducing colors");
+                    reduce_colors(&mut image, &palette, metric, false);
+                }
+            }
+            indexed
+                .then(|| {
+                    let palette_at = |_, _| 0;
+                    IndexedImage::new(
+                        &image,
+                        slice::from_ref(&slots),
+                        palette_at,
+                        reserve_transparent,
+                    )
+                })
+                .transpose()?
+        }
+        Mode::Dmg => {
+            let ramp = ramp.unwrap_or_else(|| preset.ramp());
+            info!("finding shades");
+            to_grayscale(&mut image);
+            let levels = find_shades(&image, shades, transparent);
+            info!("found {} shades", levels.len());
+            // the palette as saved, from the lightest shade, which is color 0 of the Game Boy
+            let slots = ramp.iter().rev().copied().collect::<Vec<_>>();
+            if let Some(path) = &palette_out {
+                save_palette(path, slice::from_ref(&slots))?;
+                info!("saved palette to {}", path);
+            }
+            // from the lightest, so that a pixel halfway between two levels takes the lighter one,
+            // and each pixel takes the level of its range with `Shades::Thresholds`
+            let candidates = levels.iter().rev().copied().collect::<Vec<_>>();
+            match dither {
+                Some(dither) => {
+                    info!("dithering shades");
+                    dither_colors(&mut image, &dither, Metric::Rgb, |_, _| &candidates);
+                }
+                None => {
+                    info!("reducing shades");
+                    reduce_colors(&mut image, &candidates, Metric::Rgb, true);
+                }
+            }
+            apply_ramp(&mut image, &levels, &ramp);
+            indexed
+                .then(|| IndexedImage::new(&image, slice::from_ref(&slots), |_, _| 0, false))
+                .transpose()?
+        }
+        Mode::Background | Mode::Sprite | Mode::Hicolor => {
+            // sprites always reserve color 0, which is transparent
+            let (tile_height, reserve_transparent) = match mode {
+                Mode::Sprite => (sprite_size.height(), true),
+                _ => (TILE_SIZE, reserve_transparent),
+            };
+            info!("finding tile palettes");
+            let options = TileOptions {
+                tile_height,
+                num_palettes: NUM_PALETTES,
+                transparent,
+                reserve_transparent,
+            };
+            let (tile_palettes, schedule) = match mode {
+                Mode::Hicolor => {
+                    let hicolor = find_hicolor_palettes(
+                        &image,
+                        &options,
+                        band_rows,
+                        palette_writes as usize,
+                        metric,
+                        &*quantizer,
+                        &kmeans,
+                    );
+                    info!(
+                        "scheduled {} palette color writes",
+                        hicolor.schedule.write_count()
+                    );
+                    (hicolor.tile_palettes, Some(hicolor.schedule))
+                }
+                _ => {
+                    let tile_palettes =
+                        find_tile_palettes(&image, &options, metric, &*quantizer, &kmeans);
+                    (tile_palettes, None)
+                }
+            };
+            info!(
+                "found {} unique colors in {} palettes",
+                tile_palettes
+                    .palettes
+                    .iter()
+                    .flatten()
+                    .collect::<HashSet<_>>()
+                    .len(),
+                tile_palettes.palettes.len()
+            );
+            let original = overlay.then(|| image.clone());
+            match dither {
+                Some(dither) => {
+                    info!("dithering colors per tile");
+                    dither_colors(&mut image, &dither, metric, |x, y| {
+                        &tile_palettes.palettes[tile_palettes.palette_at(x, y)]
+                    });
+                }
+                None => {
+                    info!("reducing colors per tile");
+                    reduce_tile_colors(&mut image, &tile_palettes, metric);
+                }
+            }
+            if mode == Mode::Sprite {
+                info!("building sprite data");
+                let sprite_data = build_sprite_data(&image, &tile_palettes)?;
+                info!(
+                    "{} sprites using {} unique tiles",
+                    sprite_data.oam.len(),
+                    sprite_data.tiles.len()
+                );
+                if let Some(format) = export {
+                    for path in export_sprite_data(&sprite_data, &output, format)? {
+                        info!("exported {}", path.display());
+                    }
+                }
+            } else if let Some(format) = export {
+                info!("building tile data");
+                let tile_data = build_tile_data(&mut image, &tile_palettes, merge_threshold)?;
+                if tile_data.unmerged_tiles > tile_data.tiles.len() {
+                    info!(
+                        "merged {} unique tiles into {}",
+                        tile_data.unmerged_tiles,
+                        tile_data.tiles.len()
+                    );
+                }
+                info!("{} unique tiles", tile_data.tiles.len());
+                for path in export_tile_data(&tile_data, schedule.as_ref(), &output, format)? {
+                    info!("exported {}", path.display());
+                }
+            }
+            let overlay = original.map(|original| {
+                info!("finding sprite overlay");
+                find_overlay(&original, &image, metric, &*quantizer, &kmeans)
+            });
+
+            // the sprite palettes follow the background palettes
+            let mut palettes = tile_palettes.padded_palettes();
+            if let Some(overlay) = &overlay {
+                palettes.extend(overlay.tile_palettes.padded_palettes());
+            }
+            if let Some(path) = &palette_out {
+                save_palette(path, &palettes)?;
+                info!("saved palettes to {}", path);
+            }
+            let indexed_image = indexed
+                .then(|| {
+                    IndexedImage::new(
+                        &image,
+                        &tile_palettes.padded_palettes(),
+                        |x, y| tile_palettes.palette_at(x, y),
+                        reserve_transparent,
+                    )
+                })
+                .transpose()?;
+
+            match overlay {
+                Some(overlay) => {
+                    let sprite_data = build_sprite_data(&overlay.layer, &overlay.tile_palettes)?;
+                    info!(
+                        "overlaid {} sprites using {} unique tiles",
+                        sprite_data.oam.len(),
+                        sprite_data.tiles.len()
+                    );
+                    let sprite_output = layer_path(&output, "obj");
+                    if let Some(format) = export {
+                        for path in export_sprite_data(&sprite_data, &sprite_output, format)? {
+                            info!("exported {}", path.display());
+                        }
+                    }
+
+                    let background_output = layer_path(&output, "bg");
+                    save_image(
+                        &image,
+                        indexed_image,
+                        &background_output,
+                        (width, height),
+                        block,
+                    )?;
+                    let sprite_palettes = &overlay.tile_palettes;
+                    let indexed_layer = indexed
+                        .then(|| {
+                            IndexedImage::new(
+                                &overlay.layer,
+                                &sprite_palettes.padded_palettes(),
+                                |x, y| sprite_palettes.palette_at(x, y),
+                                true,
+                            )
+                        })
+                        .transpose()?;
+                    save_image(
+                        &overlay.layer,
+                        indexed_layer,
+                        &sprite_output,
+                        (width, height),
+                        block,
+                    )?;
+
+                    image = overlay.composite(&image);
+                    let offset = tile_palettes.palettes.len();
+                    indexed
+                        .then(|| {
+                            let palette_at = |x, y| match overlay.layer.get_pixel(x, y)[3] {
+                                0 => tile_palettes.palette_at(x, y),
+                                _ => offset + sprite_palettes.palette_at(x, y),
+                            };
+                            IndexedImage::new(&image, &palettes, palette_at, reserve_transparent)
+                        })
+                        .transpose()?
+                }
+                None => indexed_image,
+            }
+        }
+    };
+    save_image(&image, indexed_image, &output, (width, height), block)?;
 
     Ok(())
 }
 
-/// Returns a pixelated version of an image.
+/// Upscales an image to the output size, along with its indexed version if any, and saves the
+/// indexed version if there is one, or the image otherwise.
 ///
-/// This function opens an image file from the given path, scales it down using the given pixelation
-/// factor, and then scales it back up to the original size to create a pixelated effect.
+/// # Arguments
+///
+/// - `image` - A reference to the image, one pixel per logical pixel.
+/// - `indexed_image` - The indexed version of the image, if the output is indexed.
+/// - `path` - The path to the output image.
+/// - `(width, height)` - The size of the output image.
+/// - `block` - The size of a logical pixel in the output image.
+fn save_image(
+    image: &Image,
+    mut indexed_image: Option<IndexedImage>,
+    path: &str,
+    (width, height): (u32, u32),
+    block: u32,
+) -> Result<()> {
+    let upscaled;
+    let mut image = image;
+    if image.dimensions() != (width, height) {
+        info!("upscaling image to {}x{}", width, height);
+        upscaled = upscale(image, width, height, block);
+        image = &upscaled;
+        if let Some(indexed_image) = &mut indexed_image {
+            indexed_image.upscale(width, height, block);
+        }
+    }
+    info!("saving image to {}", path);
+    match indexed_image {
+        Some(indexed_image) => {
+            info!("using {} indexed colors", indexed_image.palette.len());
+            indexed_image.save(path)?;
+        }
+        None => image.save(path)?,
+    }
+    Ok(())
+}
+
+/// Returns the path of a layer of the output image, named after it with a suffix, e.g.
+/// `image-bg.png` for the `bg` layer of `image.png`.
+fn layer_path(output: &str, layer: &str) -> String {
+    let path = Path::new(output);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{stem}-{layer}.{}", extension.to_string_lossy()),
+        None => format!("{stem}-{layer}"),
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
+
+/// Returns a downscaled version of an image, with one pixel per logical pixel.
+///
+/// This function opens an image file from the given path and scales it down using the given
+/// pixelation factor. The colors are reduced on the small image, which is only scaled back up to
+/// the original size at the very end to create a pixelated effect.
 ///
 /// # Arguments
 ///
 /// - `image_path` - A str representation of the path to the image file to be pixelated.
 /// - `pixelation_factor` - A u32 that represents the factor by which the image will be downscaled.
-///   Larger values result in more pixelation.
+///   Larger values result in more pixelation. It is at least 1, as `--pixelation-factor` is
+///   validated when the arguments are parsed.
+/// - `edge` - What to do with the pixels left over at the right and bottom edges when the size of
+///   the image isn't a multiple of `pixelation_factor`:
+///   - `Edge::Crop` crops them.
+///   - `Edge::Pad` extends the image with copies of its last column and row up to whole blocks.
+///   - `Edge::Partial` downscales the leftover columns and rows to one logical pixel each.
+/// - `filter` - The filter used to downscale the image.
+///
+/// # Algorithm
+///
+/// Each logical pixel is downscaled from exactly one block of `pixelation_factor` x
+/// `pixelation_factor` pixels, starting at the top left corner of the image, or from the leftover
+/// pixels of a partial block. With `Edge::Partial`, the whole blocks, the leftover columns, the
+/// leftover rows and the leftover corner are downscaled separately, so that no logical pixel mixes
+/// whole and partial blocks.
 ///
 /// # Returns
 ///
-/// - `Result<Image>` - A Result wrapping an Image type. On success, contains the pixelated Image.
-/// On failure, contains an Error detailing what went wrong.
-fn get_pixelated_image(image_path: &str, pixelation_factor: u32) -> Result<Image> {
+/// - `Result<(Image, (u32, u32))>` - A Result wrapping the downscaled Image and the size of the
+///   image it stands for: the size of the original image, or of the cropped or padded image. On
+///   failure, e.g. if `pixelation_factor` is larger than the image, contains an Error detailing
+///   what went wrong.
+fn get_pixelated_image(
+    image_path: &str,
+    pixelation_factor: u32,
+    edge: Edge,
+    filter: Filter,
+) -> Result<(Image, (u32, u32))> {
     let image = image::open(image_path)?.into_rgba8();
     let (width, height) = (image.width(), image.height());
+    if pixelation_factor > width || pixelation_factor > height {
+        bail!("pixelation factor {pixelation_factor} is larger than the {width}x{height} image");
+    }
 
-    // Downscale the image to a smaller size
-    let small = resize(
-        &image,
-        width / pixelation_factor,
-        height / pixelation_factor,
-        FilterType::Nearest,
-    );
+    let factor = pixelation_factor;
+    let (columns, rows) = (width / factor, height / factor);
+    Ok(match edge {
+        Edge::Crop => {
+            let image = crop_imm(&image, 0, 0, columns * factor, rows * factor).to_image();
+            let small = downsample(&image, columns, rows, filter);
+            (small, image.dimensions())
+        }
+        Edge::Pad => {
+            let (columns, rows) = (width.div_ceil(factor), height.div_ceil(factor));
+            let image = Image::from_fn(columns * factor, rows * factor, |x, y| {
+                *image.get_pixel(x.min(width - 1), y.min(height - 1))
+            });
+            let small = downsample(&image, columns, rows, filter);
+            (small, image.dimensions())
+        }
+        Edge::Partial => {
+            // (start, size) of the whole blocks and of the partial block along each axis
+            let parts = |size: u32, blocks: u32| {
+                [(0, blocks * factor), (blocks * factor, size % factor)]
+                    .into_iter()
+                    .filter(|&(_, size)| size > 0)
+            };
+            let mut small = Image::new(width.div_ceil(factor), height.div_ceil(factor));
+            for (x, part_width) in parts(width, columns) {
+                for (y, part_height) in parts(height, rows) {
+                    let part = crop_imm(&image, x, y, part_width, part_height).to_image();
+                    let part = downsample(
+                        &part,
+                        part_width.div_ceil(factor),
+                        part_height.div_ceil(factor),
+                        filter,
+                    );
+                    replace(&mut small, &part, (x / factor) as i64, (y / factor) as i64);
+                }
+            }
+            (small, (width, height))
+        }
+    })
+}
 
-    // Then upscale it back to the original size to get the pixelated effect
-    Ok(resize(&small, width, height, FilterType::Nearest))
+/// Upscales an image made of logical pixels, so that each logical pixel becomes a square block of
+/// pixels aligned with the top left corner.
+///
+/// # Arguments
+///
+/// - `image` - A reference to the image to be upscaled, one pixel per logical pixel.
+/// - `width` - The width of the upscaled image, in pixels.
+/// - `height` - The height of the upscaled image, in pixels.
+/// - `block` - The width and height of a logical pixel in the upscaled image. The logical pixels
+///   at the right and bottom edges are cut or stretched to fit the size of the upscaled image.
+///
+/// # Returns
+///
+/// The upscaled image.
+fn upscale<P: Pixel>(
+    image: &ImageBuffer<P, Vec<P::Subpixel>>,
+    width: u32,
+    height: u32,
+    block: u32,
+) -> ImageBuffer<P, Vec<P::Subpixel>> {
+    let (columns, rows) = image.dimensions();
+    ImageBuffer::from_fn(width, height, |x, y| {
+        *image.get_pixel((x / block).min(columns - 1), (y / block).min(rows - 1))
+    })
+}
+
+/// Makes the alpha of each pixel binary, since the Game Boy Color can't blend colors.
+///
+/// # Arguments
+///
+/// - `image` - A mutable reference to the image, which is changed in place.
+/// - `alpha_threshold` - Pixels with a lower alpha become fully transparent, and the others fully
+///   opaque.
+/// - `matte` - If set, each pixel is blended onto this color according to its alpha and becomes
+///   opaque instead, and `alpha_threshold` is ignored.
+fn flatten_alpha(image: &mut Image, alpha_threshold: u8, matte: Option<Rgb<u8>>) {
+    for pixel in image.pixels_mut() {
+        *pixel = match matte {
+            Some(matte) => {
+                let alpha = pixel[3] as f32 / 255.0;
+                let blend = |c: usize| {
+                    (pixel[c] as f32 * alpha + matte[c] as f32 * (1.0 - alpha)).round() as u8
+                };
+                Rgba([blend(0), blend(1), blend(2), 255])
+            }
+            None => match pixel[3] < alpha_threshold {
+                true => Rgba([pixel[0], pixel[1], pixel[2], 0]),
+                false => Rgba([pixel[0], pixel[1], pixel[2], 255]),
+            },
+        };
+    }
 }
 
 /// This function aims to find a color palette in an image according to input conditions.
@@ -77,40 +633,35 @@ fn get_pixelated_image(image_path: &str, pixelation_factor: u32) -> Result<Image
 /// - `num_colors` - The desired number of colors in the resulting color palette.
 /// - `transparent` - A boolean value that indicates whether transparent pixels should be included
 ///   in the color palette.
+/// - `quantizer` - The color quantization algorithm.
+/// - `locked` - Colors which must be in the palette. If there are any, the other colors are found
+///   with k-means by `lock_palette`, whatever the quantizer.
+/// - `kmeans` - The parameters of the k-means clustering used to refill the palette.
 ///
 /// # Returns
 ///
 /// A `Result` which is `Ok` when the palette could be found successfully. The `Ok` variant wraps a
 /// `Vec` of `Rgb`. Each `Rgb` instance represents a color from the palette. In case of an error,
 /// the `Err` variant is returned.
-fn find_palette(image: &Image, num_colors: usize, transparent: bool) -> Result<Vec<Rgb<u8>>> {
-    let img_vec: &[Srgba<u8>] = image.as_raw().components_as();
-
-    let rgb_pixels = img_vec
-        .iter()
-        .filter(|&pixel| !transparent || pixel.alpha == 255)
-        .map(|pixel| Srgb::<f32>::from_color(pixel.into_format::<_, f32>()))
-        .collect::<Vec<_>>();
+fn find_palette(
+    image: &Image,
+    num_colors: usize,
+    transparent: bool,
+    quantizer: &dyn Quantizer,
+    locked: &[Rgb<u8>],
+    kmeans: &KmeansOptions,
+) -> Result<Vec<Rgb<u8>>> {
+    let histogram = color_histogram(
+        image
+            .pixels()
+            .filter(|&pixel| transparent || pixel[3] == 255)
+            .map(|pixel| pixel.to_rgb()),
+    );
 
-    Ok(get_kmeans(num_colors, 1, 5.0, false, &rgb_pixels, 0)
-        .centroids
-        .iter()
-        .map(|&color| {
-            Rgb([
-                (color.red * 255f32) as u8,
-                (color.green * 255f32) as u8,
-                (color.blue * 255f32) as u8,
-            ])
-        })
-        .map(|color| {
-            // reduce the color to 5 bits per channel, means 15-bit color
-            Rgb([
-                (color[0] >> 3) << 3,
-                (color[1] >> 3) << 3,
-                (color[2] >> 3) << 3,
-            ])
-        })
-        .collect())
+    Ok(match locked.is_empty() {
+        true => quantize_palette(quantizer, &histogram, num_colors, kmeans),
+        false => lock_palette(locked, &histogram, num_colors, kmeans),
+    })
 }
 
 /// Reduces the colors of an image based on a provided color palette. The pixels of the image
@@ -121,21 +672,28 @@ fn find_palette(image: &Image, num_colors: usize, transparent: bool) -> Result<V
 /// - `image` - A mutable reference to the image that will be reduced in colors.
 /// - `palette` - A slice of `Rgb<u8>` color values that will serve as the palette for color
 ///   reduction.
+/// - `metric` - The color distance metric.
+/// - `exact` - Whether each pixel is matched from its exact 8-bit color, e.g. when a difference
+///   of less than a 15-bit step decides its color, instead of through the `ColorLut`.
 ///
 /// # Algorithm
 ///
-/// Each pixel of the image is compared to each color in the palette by calculating the squared
-/// distance between the pixel color and the palette color. The color with the minimum distance
-/// squared is considered the closest and therefore used as the new color for the pixel.
+/// The closest palette color of each 15-bit color is computed once with the given metric, by
+/// calculating the squared distance to each color in the palette, and stored in a `ColorLut`.
+/// Each pixel of the image is then rounded to 15-bit and replaced with its closest color with a
+/// single lookup, whatever the size of the palette. If `exact` is set, the closest color of each
+/// pixel is found with `find_closest_color` instead, which picks the first of equally close
+/// colors.
 ///
 /// If the palette is empty, all pixel colors will become black (`Rgb([0, 0, 0])`).
-fn reduce_colors(image: &mut Image, palette: &[Rgb<u8>]) {
+fn reduce_colors(image: &mut Image, palette: &[Rgb<u8>], metric: Metric, exact: bool) {
+    let lut = (!exact).then(|| ColorLut::new(palette, metric));
+
     image.enumerate_pixels_mut().for_each(|(_, _, pixel)| {
-        let closest_color = palette
-            .iter()
-            .copied()
-            .min_by_key(|&color| compute_squared_distance(&color, &pixel.to_rgb()))
-            .unwrap_or_else(|| Rgb([0, 0, 0]));
+        let closest_color = match &lut {
+            Some(lut) => lut.closest_color(&pixel.to_rgb()),
+            None => find_closest_color(palette, &pixel.to_rgb(), metric),
+        };
 
         *pixel = Rgba([
             closest_color[0],
@@ -146,24 +704,26 @@ fn reduce_colors(image: &mut Image, palette: &[Rgb<u8>]) {
     });
 }
 
-/// Computes the squared Euclidean distance between two colors.
-///
-/// It computes the distance using the formula `(dr * dr + dg * dg + db * db)`
-/// where `dr`, `dg`, and `db` are the differences of the RGB values of the two colors
+/// Finds the color in a palette which is closest to the given color.
 ///
 /// # Arguments
 ///
-/// * `first_color` - An Rgb<u8> color.
-/// * `second_color` - An Rgb<u8> color.
+/// - `palette` - A slice of `Rgb<u8>` color values to choose from.
+/// - `color` - The color to be matched.
+/// - `metric` - The color distance metric.
 ///
 /// # Returns
 ///
-/// * An `u32` - The computed squared Euclidean distance.
-fn compute_squared_distance(first_color: &Rgb<u8>, second_color: &Rgb<u8>) -> u32 {
-    // cast to i32 to avoid subtraction overflow
-    let red_diff = first_color[0] as i32 - second_color[0] as i32;
-    let green_diff = first_color[1] as i32 - second_color[1] as i32;
-    let blue_diff = first_color[2] as i32 - second_color[2] as i32;
-
-    (red_diff.pow(2) + green_diff.pow(2) + blue_diff.pow(2)) as u32
+/// The palette color with the minimum squared distance to `color`, or black (`Rgb([0, 0, 0])`)
+/// if the palette is empty.
+fn find_closest_color(palette: &[Rgb<u8>], color: &Rgb<u8>, metric: Metric) -> Rgb<u8> {
+    palette
+        .iter()
+        .copied()
+        .min_by(|a, b| {
+            metric
+                .compute_squared_distance(a, color)
+                .total_cmp(&metric.compute_squared_distance(b, color))
+        })
+        .unwrap_or(Rgb([0, 0, 0]))
 }
@@ -0,0 +1,142 @@
+use image::{Pixel, Rgba};
+
+use crate::{
+    args::Metric,
+    cluster::KmeansOptions,
+    find_closest_color,
+    quantize::Quantizer,
+    sprites::{fits_oam, MAX_SPRITES, MAX_SPRITES_PER_LINE},
+    tiles::{find_tile_palettes, TileOptions, TilePalettes, NUM_PALETTES, TILE_SIZE},
+    Image,
+};
+
+/// 8x8 sprites overlaid on a background, to add colors where its tile palettes fall short.
+#[derive(Debug, Clone)]
+pub struct Overlay {
+    /// The sprite palettes, with color 0 reserved for transparent pixels, and the palette
+    /// assigned to each tile of the image.
+    pub tile_palettes: TilePalettes,
+    /// The sprite layer, whose pixels drawn by sprites are opaque and the others transparent.
+    pub layer: Image,
+}
+
+impl Overlay {
+    /// Returns the image displayed with the sprites drawn over the background.
+    pub fn composite(&self, background: &Image) -> Image {
+        Image::from_fn(background.width(), background.height(), |x, y| {
+            let sprite = *self.layer.get_pixel(x, y);
+            match sprite[3] {
+                0 => *background.get_pixel(x, y),
+                _ => sprite,
+            }
+        })
+    }
+}
+
+/// Finds 8x8 sprites to overlay on a background whose colors have been reduced, where they reduce
+/// the error the most.
+///
+/// # Arguments
+///
+/// - `original` - A reference to the image before its colors were reduced.
+/// - `background` - A reference to the image reduced with the background tile palettes.
+/// - `metric` - The color distance metric used to measure errors.
+/// - `quantizer` - The color quantization algorithm used to find the sprite palettes.
+/// - `kmeans` - The parameters of the k-means clustering of tiles.
+///
+/// # Algorithm
+///
+/// The error of each tile is the sum of squared distances between its opaque pixels in the
+/// original image and in the background. The tiles with the highest error are covered with one
+/// sprite each, skipping tiles which would exceed `MAX_SPRITES_PER_LINE` sprites in their row or
+/// lie beyond the range of OAM coordinates, until `MAX_SPRITES` tiles are covered. Sprite
+/// palettes of 3 colors plus transparency are then found like background palettes, from the
+/// pixels of these tiles which the background doesn't reproduce exactly. A sprite pixel is drawn
+/// only where its closest color in the sprite palette is closer to the original than the
+/// background, and is transparent elsewhere, so each covered tile can show up to 7 colors.
+///
+/// # Returns
+///
+/// The sprite palettes and the sprite layer.
+pub fn find_overlay(
+    original: &Image,
+    background: &Image,
+    metric: Metric,
+    quantizer: &dyn Quantizer,
+    kmeans: &KmeansOptions,
+) -> Overlay {
+    let error = |x: u32, y: u32| {
+        let pixel = original.get_pixel(x, y);
+        match pixel[3] {
+            255 => metric
+                .compute_squared_distance(&pixel.to_rgb(), &background.get_pixel(x, y).to_rgb()),
+            _ => 0.0,
+        }
+    };
+
+    let columns = original.width().div_ceil(TILE_SIZE);
+    let rows = original.height().div_ceil(TILE_SIZE);
+    let mut errors = (0..rows)
+        .flat_map(|row| (0..columns).map(move |column| (column, row)))
+        .map(|(column, row)| {
+            let (left, top) = (column * TILE_SIZE, row * TILE_SIZE);
+            let error = (top..(top + TILE_SIZE).min(original.height()))
+                .flat_map(|y| (left..(left + TILE_SIZE).min(original.width())).map(move |x| (x, y)))
+                .map(|(x, y)| error(x, y) as f64)
+                .sum::<f64>();
+            ((column, row), error)
+        })
+        .filter(|&((column, row), error)| {
+            error > 0.0 && fits_oam(column * TILE_SIZE, row * TILE_SIZE)
+        })
+        .collect::<Vec<_>>();
+    errors.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+
+    let mut covered = vec![false; (columns * rows) as usize];
+    let mut per_row = vec![0; rows as usize];
+    let mut count = 0;
+    for ((column, row), _) in errors {
+        if count == MAX_SPRITES {
+            break;
+        }
+        if per_row[row as usize] < MAX_SPRITES_PER_LINE {
+            covered[(row * columns + column) as usize] = true;
+            per_row[row as usize] += 1;
+            count += 1;
+        }
+    }
+
+    // the pixels the sprites could improve, from which the sprite palettes are found
+    let is_covered = |x: u32, y: u32| covered[(y / TILE_SIZE * columns + x / TILE_SIZE) as usize];
+    let residual = Image::from_fn(original.width(), original.height(), |x, y| {
+        match is_covered(x, y) && error(x, y) > 0.0 {
+            true => *original.get_pixel(x, y),
+            false => Rgba([0, 0, 0, 0]),
+        }
+    });
+    let options = TileOptions {
+        tile_height: TILE_SIZE,
+        num_palettes: NUM_PALETTES,
+        transparent: false,
+        reserve_transparent: true,
+    };
+    let tile_palettes = find_tile_palettes(&residual, &options, metric, quantizer, kmeans);
+
+    let layer = Image::from_fn(original.width(), original.height(), |x, y| {
+        let pixel = residual.get_pixel(x, y);
+        if pixel[3] == 0 {
+            return Rgba([0, 0, 0, 0]);
+        }
+        let palette = &tile_palettes.palettes[tile_palettes.palette_at(x, y)];
+        let color = find_closest_color(palette, &pixel.to_rgb(), metric);
+        match metric.compute_squared_distance(&color, &pixel.to_rgb()) < error(x, y) {
+            true => Rgba([color[0], color[1], color[2], 255]),
+            false => Rgba([0, 0, 0, 0]),
+        }
+    });
+
+    Overlay {
+        tile_palettes,
+        layer,
+    }
+}
@@ -0,0 +1,290 @@
+use std::{fmt::Write as _, fs, path::Path};
+
+use anyhow::{bail, Context, Result};
+use image::{Pixel, Rgb};
+
+use crate::{
+    args::parse_color,
+    cluster::color_histogram,
+    vram::{to_15bit, to_5bit, to_bgr555},
+};
+
+/// Number of colors in an Adobe Color Table.
+const ACT_COLORS: usize = 256;
+
+/// Loads a palette from a file, in a format chosen by the extension of the file:
+///
+/// - `.gpl` - A GIMP palette, with one `R G B [name]` line for each color after the header.
+/// - `.act` - An Adobe Color Table, with 256 RGB triplets, optionally followed by the big-endian
+///   number of colors in use and the index of the transparent color, which is ignored.
+/// - `.pal` - A JASC palette, with the `JASC-PAL` header, the version, the number of colors and
+///   one `R G B` line for each color.
+/// - `.hex` and `.txt` - A plain list of hex colors like `#f8f8f8` or `f8f8f8`, one per line.
+/// - `.png` - A swatch strip, whose opaque colors are read in order of first occurrence, row by
+///   row, whatever the size of the swatches.
+///
+/// # Arguments
+///
+/// - `path` - The path to the palette file.
+///
+/// # Returns
+///
+/// A `Result` with the colors of the palette in file order, each rounded to 5 bits per channel
+/// and expanded back to 8 bits. Colors which round to an earlier color are dropped. It is an error
+/// if the file can't be read or parsed, or has no colors.
+pub fn load_palette(path: &str) -> Result<Vec<Rgb<u8>>> {
+    let colors = match extension(path).as_deref() {
+        Some("gpl") => parse_gpl(&read_text(path)?)?,
+        Some("act") => parse_act(&fs::read(path)?)?,
+        Some("pal") => parse_jasc(&read_text(path)?)?,
+        Some("hex" | "txt") => parse_hex(&read_text(path)?)?,
+        Some("png") => {
+            let image = image::open(path)?.into_rgba8();
+            color_histogram(
+                image
+                    .pixels()
+                    .filter(|pixel| pixel[3] > 0)
+                    .map(|pixel| pixel.to_rgb()),
+            )
+            .into_iter()
+            .map(|(color, _)| color)
+            .collect()
+        }
+        _ => bail!(
+            "unsupported palette format `{path}`, expected .gpl, .act, .pal, .hex, .txt or .png"
+        ),
+    };
+    if colors.is_empty() {
+        bail!("no colors in palette `{path}`");
+    }
+
+    let mut palette = Vec::with_capacity(colors.len());
+    for color in colors {
+        let color = to_15bit(color.0.map(f32::from));
+        if !palette.contains(&color) {
+            palette.push(color);
+        }
+    }
+    Ok(palette)
+}
+
+/// Saves palettes to a file, in a format chosen by the extension of the file:
+///
+/// - `.gpl` - A GIMP palette, with as many columns as colors per palette.
+/// - `.act` - An Adobe Color Table, with the number of colors and no transparent color.
+/// - `.pal` - A JASC palette.
+/// - `.hex` and `.txt` - A list of hex colors, one per line.
+/// - `.json` - An object with a `palettes` array, each palette being an array of colors with
+///   their hex string, 8-bit channels, 5-bit channels and BGR555 word.
+/// - `.bin` - The colors as little-endian BGR555 words, as loaded into the palette memory of the
+///   Game Boy Color.
+///
+/// All formats but JSON store the colors of all palettes one after the other.
+///
+/// # Arguments
+///
+/// - `path` - The path to the palette file.
+/// - `palettes` - The palettes to be saved, which all have the same number of colors.
+///
+/// # Returns
+///
+/// A `Result` which is an error if the format isn't supported, if an Adobe Color Table would
+/// have more than 256 colors, or if the file couldn't be written.
+pub fn save_palette(path: &str, palettes: &[Vec<Rgb<u8>>]) -> Result<()> {
+    let colors = palettes.concat();
+    let columns = palettes.first().map_or(0, Vec::len);
+    let contents = match extension(path).as_deref() {
+        Some("gpl") => write_gpl(path, &colors, columns)?.into_bytes(),
+        Some("act") => write_act(&colors)?,
+        Some("pal") => write_jasc(&colors)?.into_bytes(),
+        Some("hex" | "txt") => write_hex(&colors).into_bytes(),
+        Some("json") => write_json(palettes)?.into_bytes(),
+        Some("bin") => colors
+            .iter()
+            .flat_map(|color| to_bgr555(color).to_le_bytes())
+            .collect(),
+        _ => bail!(
+            "unsupported palette format `{path}`, expected {}",
+            ".gpl, .act, .pal, .hex, .txt, .json or .bin"
+        ),
+    };
+    fs::write(path, contents).with_context(|| format!("failed to write palette `{path}`"))
+}
+
+/// Returns the lowercase extension of a path.
+fn extension(path: &str) -> Option<String> {
+    Path::new(path)
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+}
+
+fn read_text(path: &str) -> Result<String> {
+    fs::read_to_string(path).with_context(|| format!("failed to read palette `{path}`"))
+}
+
+/// Parses a GIMP palette. The header lines (`Name:`, `Columns:`) and comments are skipped.
+fn parse_gpl(text: &str) -> Result<Vec<Rgb<u8>>> {
+    let mut lines = text.lines().map(str::trim);
+    if lines.next() != Some("GIMP Palette") {
+        bail!("missing `GIMP Palette` header");
+    }
+    lines
+        .filter(|line| {
+            !line.is_empty()
+                && !line.starts_with('#')
+                && !line.starts_with("Name:")
+                && !line.starts_with("Columns:")
+        })
+        .map(parse_rgb)
+        .collect()
+}
+
+/// Parses an Adobe Color Table.
+fn parse_act(bytes: &[u8]) -> Result<Vec<Rgb<u8>>> {
+    let count = match bytes.len() {
+        768 => ACT_COLORS,
+        772 => match u16::from_be_bytes([bytes[768], bytes[769]]) as usize {
+            0 => ACT_COLORS,
+            count => count.min(ACT_COLORS),
+        },
+        length => bail!("expected 768 or 772 bytes in a color table, got {length}"),
+    };
+    Ok(bytes[..count * 3]
+        .chunks_exact(3)
+        .map(|color| Rgb([color[0], color[1], color[2]]))
+        .collect())
+}
+
+/// Parses a JASC palette. Extra lines after the declared number of colors are ignored.
+fn parse_jasc(text: &str) -> Result<Vec<Rgb<u8>>> {
+    let mut lines = text.lines().map(str::trim).filter(|line| !line.is_empty());
+    if lines.next() != Some("JASC-PAL") {
+        bail!("missing `JASC-PAL` header");
+    }
+    lines.next().context("missing JASC palette version")?;
+    let count = lines
+        .next()
+        .context("missing number of colors")?
+        .parse::<usize>()
+        .context("invalid number of colors")?;
+
+    let colors = lines
+        .take(count)
+        .map(parse_rgb)
+        .collect::<Result<Vec<_>>>()?;
+    if colors.len() < count {
+        bail!("expected {count} colors, got {}", colors.len());
+    }
+    Ok(colors)
+}
+
+/// Parses a list of hex colors. Blank lines and lines starting with `;` are skipped.
+fn parse_hex(text: &str) -> Result<Vec<Rgb<u8>>> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with(';'))
+        .map(|line| parse_color(line).map_err(anyhow::Error::msg))
+        .collect()
+}
+
+/// Parses the first three whitespace-separated decimal numbers of a line as a color.
+fn parse_rgb(line: &str) -> Result<Rgb<u8>> {
+    let mut channels = line.split_whitespace().map(str::parse::<u8>);
+    let mut channel = || -> Result<u8> {
+        channels
+            .next()
+            .with_context(|| format!("expected `R G B`, got `{line}`"))?
+            .with_context(|| format!("invalid color `{line}`"))
+    };
+    Ok(Rgb([channel()?, channel()?, channel()?]))
+}
+
+fn write_gpl(path: &str, colors: &[Rgb<u8>], columns: usize) -> Result<String> {
+    let name = Path::new(path)
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy();
+    let mut text = String::new();
+    writeln!(text, "GIMP Palette")?;
+    writeln!(text, "Name: {name}")?;
+    writeln!(text, "Columns: {columns}")?;
+    writeln!(text, "# Generated by gbc-image-transform")?;
+    for color in colors {
+        writeln!(
+            text,
+            "{:3} {:3} {:3}\t{}",
+            color[0],
+            color[1],
+            color[2],
+            to_hex(color)
+        )?;
+    }
+    Ok(text)
+}
+
+fn write_act(colors: &[Rgb<u8>]) -> Result<Vec<u8>> {
+    if colors.len() > ACT_COLORS {
+        bail!(
+            "an Adobe Color Table has at most {ACT_COLORS} colors, got {}",
+            colors.len()
+        );
+    }
+    let mut bytes = colors.iter().flat_map(|color| color.0).collect::<Vec<_>>();
+    bytes.resize(ACT_COLORS * 3, 0);
+    bytes.extend((colors.len() as u16).to_be_bytes());
+    // no transparent color
+    bytes.extend(u16::MAX.to_be_bytes());
+    Ok(bytes)
+}
+
+/// Writes a JASC palette, with the CRLF line endings of Paint Shop Pro.
+fn write_jasc(colors: &[Rgb<u8>]) -> Result<String> {
+    let mut text = String::new();
+    write!(text, "JASC-PAL\r\n0100\r\n{}\r\n", colors.len())?;
+    for color in colors {
+        write!(text, "{} {} {}\r\n", color[0], color[1], color[2])?;
+    }
+    Ok(text)
+}
+
+fn write_hex(colors: &[Rgb<u8>]) -> String {
+    colors.iter().map(|color| to_hex(color) + "\n").collect()
+}
+
+fn write_json(palettes: &[Vec<Rgb<u8>>]) -> Result<String> {
+    let mut json = String::new();
+    writeln!(json, "{{")?;
+    writeln!(json, "  \"palettes\": [")?;
+    for (index, palette) in palettes.iter().enumerate() {
+        writeln!(json, "    [")?;
+        for (color_index, color) in palette.iter().enumerate() {
+            let [red, green, blue] = color.0;
+            let [red5, green5, blue5] = color.0.map(|channel| to_5bit(channel as f32));
+            write!(
+                json,
+                "      {{ \"hex\": \"{}\", \"rgb\": [{red}, {green}, {blue}], \
+                 \"rgb5\": [{red5}, {green5}, {blue5}], \"bgr555\": {} }}",
+                to_hex(color),
+                to_bgr555(color)
+            )?;
+            writeln!(json, "{}", separator(color_index, palette.len()))?;
+        }
+        writeln!(json, "    ]{}", separator(index, palettes.len()))?;
+    }
+    writeln!(json, "  ]")?;
+    writeln!(json, "}}")?;
+    Ok(json)
+}
+
+/// Returns the comma which follows each element of a JSON array but the last.
+fn separator(index: usize, len: usize) -> &'static str {
+    match index + 1 < len {
+        true => ",",
+        false => "",
+    }
+}
+
+/// Formats a color as a hex string, e.g. `#f8f8f8`.
+fn to_hex(color: &Rgb<u8>) -> String {
+    format!("#{:02x}{:02x}{:02x}", color[0], color[1], color[2])
+}
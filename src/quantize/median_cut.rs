@@ -0,0 +1,106 @@
+use image::Rgb;
+
+use super::Quantizer;
+
+/// Median cut quantization.
+///
+/// # Algorithm
+///
+/// All colors start in a single box. Until there are enough boxes, the box whose colors have the
+/// highest sum of squared distances to their mean is split in two along its longest side, at the
+/// weighted median of its colors. The mean of each box is a palette color.
+///
+/// Splitting the box with the highest error rather than the most pixels or the largest volume
+/// spends more colors on busy areas of the color space. It is deterministic and fast.
+#[derive(Debug, Clone, Copy)]
+pub struct MedianCut;
+
+impl Quantizer for MedianCut {
+    fn quantize(&self, histogram: &[(Rgb<u8>, u32)], num_colors: usize) -> Vec<[f32; 3]> {
+        let mut boxes = vec![ColorBox::new(histogram.to_vec())];
+        while boxes.len() < num_colors {
+            let largest = boxes
+                .iter()
+                .enumerate()
+                .filter(|(_, color_box)| color_box.colors.len() > 1)
+                .max_by(|(_, a), (_, b)| a.error.total_cmp(&b.error))
+                .map(|(index, _)| index);
+            let Some(largest) = largest else {
+                break;
+            };
+            let (first, second) = boxes.swap_remove(largest).split();
+            boxes.push(first);
+            boxes.push(second);
+        }
+        boxes.iter().map(|color_box| color_box.mean).collect()
+    }
+}
+
+/// A box of colors with their number of occurrences.
+struct ColorBox {
+    colors: Vec<(Rgb<u8>, u32)>,
+    /// The mean of the colors, weighted by their number of occurrences.
+    mean: [f32; 3],
+    /// The sum of squared distances between each occurrence of a color and the mean.
+    error: f32,
+}
+
+impl ColorBox {
+    fn new(colors: Vec<(Rgb<u8>, u32)>) -> Self {
+        let weight = colors.iter().map(|&(_, count)| count as f32).sum::<f32>();
+        let mean = [0, 1, 2].map(|c| {
+            colors
+                .iter()
+                .map(|&(color, count)| color[c] as f32 * count as f32)
+                .sum::<f32>()
+                / weight
+        });
+        let error = colors
+            .iter()
+            .map(|&(color, count)| {
+                let distance = (0..3)
+                    .map(|c| (color[c] as f32 - mean[c]).powi(2))
+                    .sum::<f32>();
+                distance * count as f32
+            })
+            .sum();
+        ColorBox {
+            colors,
+            mean,
+            error,
+        }
+    }
+
+    /// Splits the box in two along the channel with the largest range, at the weighted median.
+    /// The box must have at least two colors, and both halves have at least one.
+    fn split(mut self) -> (Self, Self) {
+        let channel = (0..3)
+            .max_by_key(|&c| {
+                let values = self.colors.iter().map(|(color, _)| color[c]);
+                values.clone().max().unwrap_or(0) - values.min().unwrap_or(0)
+            })
+            .unwrap_or(0);
+        self.colors
+            .sort_unstable_by_key(|(color, _)| color[channel]);
+
+        let half = self
+            .colors
+            .iter()
+            .map(|&(_, count)| count as u64)
+            .sum::<u64>()
+            / 2;
+        let mut weight = 0;
+        let median = self
+            .colors
+            .iter()
+            .position(|&(_, count)| {
+                weight += count as u64;
+                weight > half
+            })
+            .unwrap_or(0);
+        let second = self
+            .colors
+            .split_off((median + 1).clamp(1, self.colors.len() - 1));
+        (ColorBox::new(self.colors), ColorBox::new(second))
+    }
+}
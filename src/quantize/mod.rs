@@ -0,0 +1,85 @@
+mod median_cut;
+mod neuquant;
+mod octree;
+mod wu;
+
+use image::Rgb;
+
+use crate::{
+    args::Quantization,
+    cluster::{refill_palette, KmeansOptions},
+    vram::to_15bit,
+};
+
+pub use self::{median_cut::MedianCut, neuquant::NeuQuant, octree::Octree, wu::Wu};
+
+/// A color quantization algorithm, which finds a few colors representing many.
+pub trait Quantizer {
+    /// Finds up to `num_colors` colors representing the colors of a histogram.
+    ///
+    /// # Arguments
+    ///
+    /// - `histogram` - The unique colors to be represented with their number of occurrences, as
+    ///   returned by `color_histogram`. It is never empty.
+    /// - `num_colors` - The maximum number of colors to be returned.
+    ///
+    /// # Returns
+    ///
+    /// A `Vec` of sRGB colors with 8-bit channels, which may be fractional or out of range, and
+    /// don't need to be distinct.
+    fn quantize(&self, histogram: &[(Rgb<u8>, u32)], num_colors: usize) -> Vec<[f32; 3]>;
+}
+
+impl Quantization {
+    /// Returns the quantizer running this algorithm. k-means runs with the given parameters.
+    pub fn quantizer(self, kmeans: KmeansOptions) -> Box<dyn Quantizer> {
+        match self {
+            Quantization::Kmeans => Box::new(kmeans),
+            Quantization::MedianCut => Box::new(MedianCut),
+            Quantization::Octree => Box::new(Octree),
+            Quantization::Wu => Box::new(Wu),
+            Quantization::Neuquant => Box::new(NeuQuant),
+        }
+    }
+}
+
+/// Finds a palette of 15-bit colors for the colors of a histogram.
+///
+/// # Arguments
+///
+/// - `quantizer` - The color quantization algorithm.
+/// - `histogram` - The unique colors to be represented with their number of occurrences, as
+///   returned by `color_histogram`.
+/// - `num_colors` - The maximum number of colors in the resulting palette.
+/// - `kmeans` - The parameters of the k-means clustering used to refill the palette.
+///
+/// # Algorithm
+///
+/// The colors found by the quantizer are rounded to 15-bit, and only the first occurrence of each
+/// color is kept. The freed slots are refilled with `refill_palette`.
+///
+/// # Returns
+///
+/// A `Vec` of distinct `Rgb` colors, each rounded to 5 bits per channel and expanded back to 8
+/// bits. The palette may have fewer than `num_colors` entries if there aren't enough distinct
+/// colors, and is empty if `histogram` is empty.
+pub fn quantize_palette(
+    quantizer: &dyn Quantizer,
+    histogram: &[(Rgb<u8>, u32)],
+    num_colors: usize,
+    kmeans: &KmeansOptions,
+) -> Vec<Rgb<u8>> {
+    if histogram.is_empty() || num_colors == 0 {
+        return Vec::new();
+    }
+
+    let mut palette = Vec::with_capacity(num_colors);
+    for color in quantizer.quantize(histogram, num_colors) {
+        let color = to_15bit(color);
+        if !palette.contains(&color) && palette.len() < num_colors {
+            palette.push(color);
+        }
+    }
+    refill_palette(&mut palette, histogram, num_colors, 0, kmeans);
+    palette
+}
@@ -0,0 +1,45 @@
+use std::iter;
+
+use image::Rgb;
+
+use super::Quantizer;
+
+/// NeuQuant learns at least 1 in `MAX_SAMPLE_FACTOR` pixels.
+const MAX_SAMPLE_FACTOR: usize = 10;
+
+/// NeuQuant learns at least this many pixels, or all pixels of smaller images.
+const MIN_SAMPLES: usize = 10_000;
+
+/// NeuQuant quantization, from Anthony Dekker, "Kohonen neural networks for optimal colour
+/// quantization", 1994, with the implementation of the `color_quant` crate.
+///
+/// # Algorithm
+///
+/// A one-dimensional self-organizing map of `num_colors` neurons learns a sample of the pixels:
+/// for each pixel, the closest neuron and, less and less as the learning goes on, its neighbours
+/// in the map are moved towards the pixel. The neurons are the palette colors.
+///
+/// It is deterministic, and good at preserving smooth gradients, but was tuned for 64 to 256
+/// colors and is less accurate with fewer.
+#[derive(Debug, Clone, Copy)]
+pub struct NeuQuant;
+
+impl Quantizer for NeuQuant {
+    fn quantize(&self, histogram: &[(Rgb<u8>, u32)], num_colors: usize) -> Vec<[f32; 3]> {
+        // NeuQuant learns from a sequence of RGBA pixels, which it samples evenly
+        let pixels = histogram
+            .iter()
+            .flat_map(|&(color, count)| {
+                iter::repeat_n([color[0], color[1], color[2], 255], count as usize)
+            })
+            .flatten()
+            .collect::<Vec<_>>();
+        let sample_factor = (pixels.len() / 4 / MIN_SAMPLES).clamp(1, MAX_SAMPLE_FACTOR);
+
+        color_quant::NeuQuant::new(sample_factor as i32, num_colors, &pixels)
+            .color_map_rgb()
+            .chunks_exact(3)
+            .map(|color| [color[0], color[1], color[2]].map(f32::from))
+            .collect()
+    }
+}
@@ -0,0 +1,99 @@
+use image::Rgb;
+
+use super::Quantizer;
+
+/// Depth of the leaves of the tree, one level for each bit of a channel.
+const MAX_DEPTH: usize = 8;
+
+/// Octree quantization.
+///
+/// # Algorithm
+///
+/// Each color is inserted into a tree where the node at depth `d` has up to 8 children, selected
+/// by bit `7 - d` of the red, green and blue channels, so that the leaves are the exact colors.
+/// Each node counts the occurrences and sums the channels of the colors below it. Then, from the
+/// deepest level up, the nodes which stand for the fewest pixels are made leaves, merging their
+/// children, until there are no more leaves than colors. The mean color of each leaf is a palette
+/// color.
+///
+/// Merging a node with many children may leave fewer leaves than colors. It is deterministic and
+/// the fastest quantizer.
+#[derive(Debug, Clone, Copy)]
+pub struct Octree;
+
+/// A node of the tree, with the number of occurrences and the sum of the channels of the colors
+/// below it.
+#[derive(Debug, Clone, Default)]
+struct Node {
+    /// Index of each child in the tree, `0` meaning no child since the root is never a child.
+    children: [usize; 8],
+    count: u64,
+    sum: [u64; 3],
+}
+
+impl Node {
+    fn add(&mut self, color: Rgb<u8>, count: u32) {
+        self.count += count as u64;
+        for (sum, channel) in self.sum.iter_mut().zip(color.0) {
+            *sum += channel as u64 * count as u64;
+        }
+    }
+}
+
+impl Quantizer for Octree {
+    fn quantize(&self, histogram: &[(Rgb<u8>, u32)], num_colors: usize) -> Vec<[f32; 3]> {
+        let mut nodes = vec![Node::default()];
+        // the nodes with children at each depth
+        let mut levels = vec![Vec::new(); MAX_DEPTH];
+
+        for &(color, count) in histogram {
+            let mut index = 0;
+            for (depth, level) in levels.iter_mut().enumerate() {
+                nodes[index].add(color, count);
+                if nodes[index].children.iter().all(|&child| child == 0) {
+                    level.push(index);
+                }
+
+                let shift = 7 - depth;
+                let child = ((color[0] >> shift & 1) << 2
+                    | (color[1] >> shift & 1) << 1
+                    | color[2] >> shift & 1) as usize;
+                index = match nodes[index].children[child] {
+                    0 => {
+                        nodes.push(Node::default());
+                        nodes[index].children[child] = nodes.len() - 1;
+                        nodes.len() - 1
+                    }
+                    child => child,
+                };
+            }
+            nodes[index].add(color, count);
+        }
+
+        // merge the children of the least used nodes, deepest first, whose children are all leaves
+        let mut leaves = histogram.len();
+        'merge: for level in levels.iter_mut().rev() {
+            level.sort_by_key(|&index| nodes[index].count);
+            for &index in level.iter() {
+                if leaves <= num_colors {
+                    break 'merge;
+                }
+                let children = nodes[index].children.iter().filter(|&&c| c != 0).count();
+                leaves -= children - 1;
+                nodes[index].children = [0; 8];
+            }
+        }
+
+        let mut colors = Vec::with_capacity(leaves);
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            let node = &nodes[index];
+            if node.children.iter().all(|&child| child == 0) {
+                colors.push(node.sum.map(|sum| sum as f32 / node.count as f32));
+            } else {
+                stack.extend(node.children.iter().filter(|&&child| child != 0));
+            }
+        }
+        colors
+    }
+}
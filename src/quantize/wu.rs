@@ -0,0 +1,196 @@
+use image::Rgb;
+
+use super::Quantizer;
+
+/// Number of histogram cells along each channel: one for each 5-bit value, plus an empty one
+/// which makes the cumulative moments easier to compute.
+const SIZE: usize = 33;
+
+/// Wu's variance minimization quantization, from Xiaolin Wu, "Efficient Statistical Computations
+/// for Optimal Color Quantization", Graphics Gems II, 1991.
+///
+/// # Algorithm
+///
+/// The colors are counted in a 32x32x32 histogram, which matches the 15-bit colors of the Game Boy
+/// Color, and the cumulative moments of the histogram give the weight, mean and variance of any box
+/// of cells in constant time. All colors start in a single box. Until there are enough boxes, the
+/// box with the highest variance is cut in two along the channel and at the position where the sum
+/// of the variances of both halves is the lowest. The mean of each box is a palette color.
+///
+/// It is deterministic, fast, and usually close to k-means in quality.
+#[derive(Debug, Clone, Copy)]
+pub struct Wu;
+
+impl Quantizer for Wu {
+    fn quantize(&self, histogram: &[(Rgb<u8>, u32)], num_colors: usize) -> Vec<[f32; 3]> {
+        let moments = Moments::new(histogram);
+
+        let mut boxes = vec![CellBox {
+            lower: [0; 3],
+            upper: [SIZE - 1; 3],
+        }];
+        let mut variances = vec![moments.variance(&boxes[0])];
+        while boxes.len() < num_colors {
+            let Some((next, _)) = variances
+                .iter()
+                .enumerate()
+                .filter(|(_, &variance)| variance > 0.0)
+                .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            else {
+                break;
+            };
+            match moments.cut(&boxes[next]) {
+                Some((first, second)) => {
+                    variances[next] = moments.variance(&first);
+                    variances.push(moments.variance(&second));
+                    boxes[next] = first;
+                    boxes.push(second);
+                }
+                None => variances[next] = 0.0,
+            }
+        }
+
+        boxes
+            .iter()
+            .map(|cell_box| moments.sum(cell_box))
+            .filter(|sum| sum.weight > 0.0)
+            .map(|sum| sum.channels.map(|channel| (channel / sum.weight) as f32))
+            .collect()
+    }
+}
+
+/// A box of histogram cells, from `lower` excluded to `upper` included along each channel.
+#[derive(Debug, Clone, Copy)]
+struct CellBox {
+    lower: [usize; 3],
+    upper: [usize; 3],
+}
+
+/// The moments of the colors in a box.
+#[derive(Debug, Clone, Copy, Default)]
+struct Sum {
+    /// The number of occurrences.
+    weight: f64,
+    /// The sum of each channel.
+    channels: [f64; 3],
+    /// The sum of the squared channels.
+    squares: f64,
+}
+
+impl Sum {
+    fn add(&mut self, other: &Self, sign: f64) {
+        self.weight += sign * other.weight;
+        for c in 0..3 {
+            self.channels[c] += sign * other.channels[c];
+        }
+        self.squares += sign * other.squares;
+    }
+
+    /// Returns the sum of the squared channel sums divided by the weight, which is what the sum of
+    /// squares exceeds the variance by.
+    fn spread(&self) -> f64 {
+        self.channels.iter().map(|sum| sum * sum).sum::<f64>() / self.weight
+    }
+}
+
+/// The cumulative moments of the histogram: each cell holds the moments of all colors in the box
+/// from the origin to the cell.
+struct Moments {
+    cells: Vec<Sum>,
+}
+
+impl Moments {
+    fn new(histogram: &[(Rgb<u8>, u32)]) -> Self {
+        let mut cells = vec![Sum::default(); SIZE * SIZE * SIZE];
+        for &(color, count) in histogram {
+            let [red, green, blue] = color.0.map(|channel| (channel >> 3) as usize + 1);
+            let cell = &mut cells[index(red, green, blue)];
+            let weight = count as f64;
+            let channels = color.0.map(f64::from);
+            cell.weight += weight;
+            for (sum, channel) in cell.channels.iter_mut().zip(channels) {
+                *sum += weight * channel;
+            }
+            cell.squares += weight
+                * channels
+                    .iter()
+                    .map(|channel| channel * channel)
+                    .sum::<f64>();
+        }
+
+        // accumulate along each channel in turn
+        for stride in [1, SIZE, SIZE * SIZE] {
+            for i in 0..cells.len() {
+                if i / stride % SIZE > 0 {
+                    let previous = cells[i - stride];
+                    cells[i].add(&previous, 1.0);
+                }
+            }
+        }
+
+        Moments { cells }
+    }
+
+    /// Returns the moments of the colors in a box, by inclusion–exclusion of the cumulative
+    /// moments at its corners.
+    fn sum(&self, cell_box: &CellBox) -> Sum {
+        let mut sum = Sum::default();
+        for corner in 0..8 {
+            let [red, green, blue] = [0, 1, 2].map(|c| match corner >> c & 1 {
+                0 => cell_box.upper[c],
+                _ => cell_box.lower[c],
+            });
+            let sign = match (corner as u32).count_ones() % 2 {
+                0 => 1.0,
+                _ => -1.0,
+            };
+            sum.add(&self.cells[index(red, green, blue)], sign);
+        }
+        sum
+    }
+
+    /// Returns the sum of squared distances between each color in a box and their mean.
+    fn variance(&self, cell_box: &CellBox) -> f64 {
+        let sum = self.sum(cell_box);
+        match sum.weight > 0.0 {
+            true => sum.squares - sum.spread(),
+            false => 0.0,
+        }
+    }
+
+    /// Cuts a box in two where the sum of the variances of both halves is the lowest, or returns
+    /// `None` if it can't be cut into two non-empty halves.
+    ///
+    /// Minimizing the sum of variances is the same as maximizing the sum of the spreads of both
+    /// halves, since the sum of squares doesn't change.
+    fn cut(&self, cell_box: &CellBox) -> Option<(CellBox, CellBox)> {
+        let whole = self.sum(cell_box);
+        let mut best = None;
+        let mut best_spread = 0.0;
+        for channel in 0..3 {
+            for position in cell_box.lower[channel] + 1..cell_box.upper[channel] {
+                let mut first = *cell_box;
+                first.upper[channel] = position;
+                let lower = self.sum(&first);
+                let mut upper = whole;
+                upper.add(&lower, -1.0);
+                if lower.weight <= 0.0 || upper.weight <= 0.0 {
+                    continue;
+                }
+
+                let spread = lower.spread() + upper.spread();
+                if spread > best_spread {
+                    let mut second = *cell_box;
+                    second.lower[channel] = position;
+                    best = Some((first, second));
+                    best_spread = spread;
+                }
+            }
+        }
+        best
+    }
+}
+
+fn index(red: usize, green: usize, blue: usize) -> usize {
+    (red * SIZE + green) * SIZE + blue
+}
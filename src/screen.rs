@@ -0,0 +1,103 @@
+use anyhow::{bail, Result};
+use image::{
+    imageops::{crop_imm, replace},
+    Rgb, Rgba,
+};
+
+use crate::{
+    args::{Anchor, Filter, Resize},
+    downsample::downsample,
+    Image,
+};
+
+/// Returns a version of an image resized to exactly the given screen size.
+///
+/// This function opens an image file from the given path and resizes it to `width` x `height`
+/// pixels using the given strategy. Each pixel of the result is a logical pixel of the screen.
+///
+/// # Arguments
+///
+/// - `image_path` - A str representation of the path to the image file to be resized.
+/// - `(width, height)` - The target screen size, in pixels.
+/// - `strategy` - How the aspect ratio of the image is reconciled with the screen's:
+///   - `Resize::Fit` scales the image to fit inside the screen, and fills the remaining area with
+///     `letterbox`.
+///   - `Resize::Fill` scales the image to cover the whole screen, and crops what overflows.
+///   - `Resize::Stretch` scales each axis independently, distorting the image.
+/// - `anchor` - Where the image is placed inside the screen with `Resize::Fit`, or which part of
+///   the image is kept with `Resize::Fill`.
+/// - `letterbox` - The color of the borders added by `Resize::Fit`.
+/// - `filter` - The filter used to resize the image.
+///
+/// # Returns
+///
+/// - `Result<Image>` - A Result wrapping an Image type. On success, contains the resized Image.
+///   On failure, contains an Error detailing what went wrong.
+pub fn get_screen_image(
+    image_path: &str,
+    (width, height): (u32, u32),
+    strategy: Resize,
+    anchor: Anchor,
+    letterbox: Rgb<u8>,
+    filter: Filter,
+) -> Result<Image> {
+    if width == 0 || height == 0 {
+        bail!("screen size must be greater than zero, got {width}x{height}");
+    }
+
+    let image = image::open(image_path)?.into_rgba8();
+    let (source_width, source_height) = (image.width() as f64, image.height() as f64);
+    let (scale_x, scale_y) = (width as f64 / source_width, height as f64 / source_height);
+
+    Ok(match strategy {
+        Resize::Stretch => downsample(&image, width, height, filter),
+        Resize::Fit => {
+            let scale = scale_x.min(scale_y);
+            let scaled = downsample(
+                &image,
+                ((source_width * scale).round() as u32).clamp(1, width),
+                ((source_height * scale).round() as u32).clamp(1, height),
+                filter,
+            );
+            let (x, y) = anchor.offset(width - scaled.width(), height - scaled.height());
+
+            let mut screen = Image::from_pixel(
+                width,
+                height,
+                Rgba([letterbox[0], letterbox[1], letterbox[2], 255]),
+            );
+            replace(&mut screen, &scaled, x as i64, y as i64);
+            screen
+        }
+        Resize::Fill => {
+            let scale = scale_x.max(scale_y);
+            let scaled = downsample(
+                &image,
+                ((source_width * scale).round() as u32).max(width),
+                ((source_height * scale).round() as u32).max(height),
+                filter,
+            );
+            let (x, y) = anchor.offset(scaled.width() - width, scaled.height() - height);
+
+            crop_imm(&scaled, x, y, width, height).to_image()
+        }
+    })
+}
+
+impl Anchor {
+    /// Returns the offset of the anchored point, given the horizontal and vertical room left.
+    fn offset(self, room_x: u32, room_y: u32) -> (u32, u32) {
+        let (x, y) = match self {
+            Anchor::TopLeft => (0, 0),
+            Anchor::Top => (1, 0),
+            Anchor::TopRight => (2, 0),
+            Anchor::Left => (0, 1),
+            Anchor::Center => (1, 1),
+            Anchor::Right => (2, 1),
+            Anchor::BottomLeft => (0, 2),
+            Anchor::Bottom => (1, 2),
+            Anchor::BottomRight => (2, 2),
+        };
+        (room_x * x / 2, room_y * y / 2)
+    }
+}
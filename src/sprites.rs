@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+use image::Pixel;
+
+use crate::{
+    args::SpriteSize,
+    tiles::{TilePalettes, TILE_SIZE},
+    vram::{
+        encode_2bpp, find_color_index, TilePixels, ATTRIBUTE_FLIP_X, ATTRIBUTE_FLIP_Y, TILE_BYTES,
+    },
+    Image,
+};
+
+/// Number of sprites the OAM can hold.
+pub const MAX_SPRITES: usize = 40;
+
+/// Number of sprites the Game Boy Color can draw on a single line.
+pub const MAX_SPRITES_PER_LINE: usize = 10;
+
+/// Horizontal offset of OAM coordinates: a sprite at X 8 is drawn at the left edge of the screen.
+const OAM_X_OFFSET: u32 = 8;
+
+/// Vertical offset of OAM coordinates: a sprite at Y 16 is drawn at the top edge of the screen.
+const OAM_Y_OFFSET: u32 = 16;
+
+impl SpriteSize {
+    /// Returns the height of a sprite, in logical pixels.
+    pub fn height(self) -> u32 {
+        match self {
+            SpriteSize::Small => TILE_SIZE,
+            SpriteSize::Tall => TILE_SIZE * 2,
+        }
+    }
+}
+
+/// The data to be loaded into VRAM, OAM and palette memory to display an image as sprites.
+#[derive(Debug, Clone)]
+pub struct SpriteData {
+    /// Unique tiles in 2bpp format, for VRAM bank 0. An 8x16 sprite uses two consecutive tiles,
+    /// the top one first.
+    pub tiles: Vec<[u8; TILE_BYTES]>,
+    /// OAM entries for each sprite, in row-major order: Y position + 16, X position + 8, tile
+    /// number and attributes, i.e. palette number in bits 0-2, horizontal flip in bit 5 and
+    /// vertical flip in bit 6. The positions are those of the sprite in the image, so the image
+    /// is drawn at the top left corner of the screen.
+    pub oam: Vec<[u8; 4]>,
+    /// `NUM_PALETTES` palettes of `COLORS_PER_PALETTE` colors, as BGR555 words. Color 0 of each
+    /// palette is transparent.
+    pub palettes: Vec<u16>,
+    /// Height of a sprite, in logical pixels.
+    pub sprite_height: u32,
+}
+
+/// Builds the VRAM and OAM data of an image whose colors have been reduced with
+/// `reduce_tile_colors`.
+///
+/// # Arguments
+///
+/// - `image` - A reference to the image reduced with the given tile palettes.
+/// - `tile_palettes` - The palettes and tile assignments used to reduce the image, with color 0
+///   reserved for transparent pixels, and tiles as tall as the sprites.
+///
+/// # Algorithm
+///
+/// The image is covered by a grid of sprites, starting at its top left corner, and sprites whose
+/// pixels are all transparent are left out. Transparent pixels use color 0 and the other pixels
+/// colors 1 to 3 of the sprite's palette. Sprites which are identical, possibly after a
+/// horizontal and/or vertical flip, share their tiles and use the flip bits of their attributes,
+/// even when they use different palettes. An 8x16 sprite is flipped as a whole, its two tiles
+/// swapping places when flipped vertically. Parts of a sprite outside of the image are
+/// transparent.
+///
+/// # Returns
+///
+/// A `Result` which is `Ok` with the `SpriteData` on success, or an error if the image needs more
+/// than `MAX_SPRITES` sprites, more than `MAX_SPRITES_PER_LINE` sprites on a line, or sprites
+/// beyond the range of OAM coordinates.
+pub fn build_sprite_data(image: &Image, tile_palettes: &TilePalettes) -> Result<SpriteData> {
+    let TilePalettes {
+        palettes,
+        assignments,
+        columns,
+        tile_height,
+        ..
+    } = tile_palettes;
+    let tile_origin = |tile: usize| {
+        (
+            tile as u32 % columns * TILE_SIZE,
+            tile as u32 / columns * tile_height,
+        )
+    };
+
+    let sprites = (0..assignments.len())
+        .filter(|&tile| {
+            let (left, top) = tile_origin(tile);
+            (top..top + tile_height)
+                .flat_map(|y| (left..left + TILE_SIZE).map(move |x| (x, y)))
+                .filter_map(|(x, y)| image.get_pixel_checked(x, y))
+                .any(|pixel| pixel[3] > 0)
+        })
+        .collect::<Vec<_>>();
+    if sprites.len() > MAX_SPRITES {
+        bail!(
+            "the image needs {} sprites, but at most {} can be displayed at once",
+            sprites.len(),
+            MAX_SPRITES
+        );
+    }
+    for row in 0..tile_palettes.rows {
+        let count = sprites
+            .iter()
+            .filter(|&&tile| tile as u32 / columns == row)
+            .count();
+        if count > MAX_SPRITES_PER_LINE {
+            bail!(
+                "lines {} to {} need {} sprites, but at most {} can be displayed on a line",
+                row * tile_height,
+                (row + 1) * tile_height - 1,
+                count,
+                MAX_SPRITES_PER_LINE
+            );
+        }
+    }
+
+    // deduplicate sprites in their canonical orientation, i.e. the smallest of their flips
+    let tiles_per_sprite = (tile_height / TILE_SIZE) as usize;
+    let mut unique_sprites = Vec::<Vec<u8>>::new();
+    let mut indices = HashMap::new();
+    let mut oam = Vec::with_capacity(sprites.len());
+    for tile in sprites {
+        let (left, top) = tile_origin(tile);
+        if !fits_oam(left, top) {
+            bail!("the sprite at ({left}, {top}) is beyond the range of OAM coordinates");
+        }
+
+        let palette = &palettes[assignments[tile]];
+        let pixels = (0..TILE_SIZE * tile_height)
+            .map(|i| {
+                let (x, y) = (left + i % TILE_SIZE, top + i / TILE_SIZE);
+                match image.get_pixel_checked(x, y) {
+                    Some(pixel) if pixel[3] > 0 => 1 + find_color_index(palette, &pixel.to_rgb()),
+                    _ => 0,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let (canonical, flip) = [
+            0,
+            ATTRIBUTE_FLIP_X,
+            ATTRIBUTE_FLIP_Y,
+            ATTRIBUTE_FLIP_X | ATTRIBUTE_FLIP_Y,
+        ]
+        .map(|flip| (flip_sprite(&pixels, flip), flip))
+        .into_iter()
+        .min()
+        .unwrap_or((pixels, 0));
+        let index = *indices.entry(canonical.clone()).or_insert_with(|| {
+            unique_sprites.push(canonical);
+            unique_sprites.len() - 1
+        });
+        oam.push([
+            (top + OAM_Y_OFFSET) as u8,
+            (left + OAM_X_OFFSET) as u8,
+            (index * tiles_per_sprite) as u8,
+            assignments[tile] as u8 | flip,
+        ]);
+    }
+
+    let tiles = unique_sprites
+        .iter()
+        .flat_map(|pixels| pixels.chunks_exact((TILE_SIZE * TILE_SIZE) as usize))
+        .map(|pixels| {
+            let pixels: &TilePixels = pixels.try_into().expect("a tile has 64 pixels");
+            encode_2bpp(pixels)
+        })
+        .collect();
+
+    Ok(SpriteData {
+        tiles,
+        oam,
+        palettes: tile_palettes.palette_memory(),
+        sprite_height: *tile_height,
+    })
+}
+
+/// Returns whether a sprite whose top left corner is at `(left, top)` in the image can be placed
+/// there with OAM coordinates.
+pub fn fits_oam(left: u32, top: u32) -> bool {
+    left + OAM_X_OFFSET <= u8::MAX as u32 && top + OAM_Y_OFFSET <= u8::MAX as u32
+}
+
+/// Flips the pixels of a sprite horizontally and/or vertically, according to the flip bits of an
+/// OAM attribute.
+fn flip_sprite(pixels: &[u8], flip: u8) -> Vec<u8> {
+    let width = TILE_SIZE as usize;
+    let height = pixels.len() / width;
+    (0..pixels.len())
+        .map(|i| {
+            let (mut x, mut y) = (i % width, i / width);
+            if flip & ATTRIBUTE_FLIP_X != 0 {
+                x = width - 1 - x;
+            }
+            if flip & ATTRIBUTE_FLIP_Y != 0 {
+                y = height - 1 - y;
+            }
+            pixels[y * width + x]
+        })
+        .collect()
+}
@@ -0,0 +1,314 @@
+use image::{Pixel, Rgb, Rgba};
+use kmeans_colors::get_kmeans;
+use palette::Srgb;
+
+use crate::{
+    args::Metric,
+    cluster::{color_histogram, KmeansOptions},
+    find_closest_color,
+    quantize::{quantize_palette, Quantizer},
+    vram::to_bgr555,
+    Image,
+};
+
+/// Width and height of a background tile, in logical pixels.
+pub const TILE_SIZE: u32 = 8;
+
+/// Number of background palettes the Game Boy Color can hold at once.
+pub const NUM_PALETTES: usize = 8;
+
+/// Number of colors in each background palette.
+pub const COLORS_PER_PALETTE: usize = 4;
+
+/// Maximum number of palette refinement rounds.
+pub const MAX_ROUNDS: usize = 8;
+
+/// How an image is split into tiles, and what palettes are found for them.
+#[derive(Debug, Clone, Copy)]
+pub struct TileOptions {
+    /// Height of a tile, in logical pixels: `TILE_SIZE`, or twice that for 8x16 sprites, whose two
+    /// tiles share a palette.
+    pub tile_height: u32,
+    /// Number of palettes to be found, usually `NUM_PALETTES`.
+    pub num_palettes: usize,
+    /// Whether transparent pixels are included in the palettes.
+    pub transparent: bool,
+    /// Whether color 0 of each palette is reserved for transparent pixels, leaving 3 colors to be
+    /// found.
+    pub reserve_transparent: bool,
+}
+
+/// Background or sprite palettes of an image, and the palette assigned to each of its tiles.
+#[derive(Debug, Clone)]
+pub struct TilePalettes {
+    /// Up to `NUM_PALETTES` palettes of up to `COLORS_PER_PALETTE` 15-bit colors each, or in
+    /// hicolor mode, the palettes of each band one after the other, palette `p` being loaded into
+    /// palette `p % NUM_PALETTES` of the palette memory.
+    pub palettes: Vec<Vec<Rgb<u8>>>,
+    /// Index into `palettes` for each tile, in row-major order.
+    pub assignments: Vec<usize>,
+    /// Number of tile columns.
+    pub columns: u32,
+    /// Number of tile rows.
+    pub rows: u32,
+    /// Height of a tile, in logical pixels: `TILE_SIZE`, or twice that for 8x16 sprites.
+    pub tile_height: u32,
+    /// Whether color 0 of each palette is reserved for transparent pixels, the colors of
+    /// `palettes` being colors 1 to 3.
+    pub reserve_transparent: bool,
+}
+
+impl TilePalettes {
+    /// Returns the index in `palettes` of the palette assigned to the tile of a pixel.
+    pub fn palette_at(&self, x: u32, y: u32) -> usize {
+        self.assignments[(y / self.tile_height * self.columns + x / TILE_SIZE) as usize]
+    }
+
+    /// Returns the palettes as laid out in the palette memory, each padded to
+    /// `COLORS_PER_PALETTE` colors with black, so that once concatenated, color `c` of palette `p`
+    /// is at index `p * COLORS_PER_PALETTE + c`. The reserved transparent color is black.
+    pub fn padded_palettes(&self) -> Vec<Vec<Rgb<u8>>> {
+        self.palettes
+            .iter()
+            .map(|palette| {
+                let mut padded = match self.reserve_transparent {
+                    true => vec![Rgb([0, 0, 0])],
+                    false => Vec::new(),
+                };
+                padded.extend(palette);
+                padded.resize(COLORS_PER_PALETTE, Rgb([0, 0, 0]));
+                padded
+            })
+            .collect()
+    }
+
+    /// Returns the contents of the palette memory: `NUM_PALETTES` palettes of
+    /// `COLORS_PER_PALETTE` colors as BGR555 words, laid out like `padded_palettes`, with unused
+    /// palettes black.
+    pub fn palette_memory(&self) -> Vec<u16> {
+        let padded_palettes = self.padded_palettes();
+        (0..NUM_PALETTES)
+            .flat_map(|palette| (0..COLORS_PER_PALETTE).map(move |color| (palette, color)))
+            .map(|(palette, color)| {
+                padded_palettes
+                    .get(palette)
+                    .and_then(|colors| colors.get(color))
+                    .map_or(0, to_bgr555)
+            })
+            .collect()
+    }
+}
+
+/// Finds palettes for an image and assigns one of them to each tile, so that the result can be
+/// displayed by the Game Boy Color hardware, as a background of 8x8 tiles or as sprites.
+///
+/// # Arguments
+///
+/// - `image` - A reference to the downscaled image to be split into tiles, one pixel per logical
+///   pixel.
+/// - `options` - The size of the tiles, the number of palettes, and how transparent pixels are
+///   handled.
+/// - `metric` - The color distance metric used to measure errors.
+/// - `quantizer` - The color quantization algorithm used to find the palettes.
+/// - `kmeans` - The parameters of the k-means clustering of tiles, also used to refill palettes.
+///
+/// # Algorithm
+///
+/// Tiles are first grouped by k-means on their average color, into one group per palette. Then,
+/// until the assignment is stable or `MAX_ROUNDS` is reached, a palette of 4 colors, or 3 if color
+/// 0 is reserved, is computed by the quantizer from the color histogram of each group, and every
+/// tile is moved to the palette which reproduces it with the smallest error. A palette left
+/// without tiles is rebuilt from the tile that is worst represented by the other palettes.
+pub fn find_tile_palettes(
+    image: &Image,
+    options: &TileOptions,
+    metric: Metric,
+    quantizer: &dyn Quantizer,
+    kmeans: &KmeansOptions,
+) -> TilePalettes {
+    let TileOptions {
+        tile_height,
+        num_palettes,
+        transparent,
+        reserve_transparent,
+    } = *options;
+    let num_colors = COLORS_PER_PALETTE - reserve_transparent as usize;
+    let columns = image.width().div_ceil(TILE_SIZE);
+    let rows = image.height().div_ceil(tile_height);
+    let tiles = (0..rows)
+        .flat_map(|row| (0..columns).map(move |column| (column, row)))
+        .map(|(column, row)| {
+            let origin = (column * TILE_SIZE, row * tile_height);
+            get_tile_colors(image, origin, tile_height, transparent)
+        })
+        .collect::<Vec<_>>();
+
+    let mut assignments = group_tiles(&tiles, num_palettes, kmeans.seed);
+    let mut palettes = vec![Vec::new(); num_palettes];
+
+    for _ in 0..MAX_ROUNDS {
+        for (index, palette) in palettes.iter_mut().enumerate() {
+            let histogram = color_histogram(
+                tiles
+                    .iter()
+                    .zip(&assignments)
+                    .filter(|(_, &assignment)| assignment == index)
+                    .flat_map(|(colors, _)| colors.iter().copied()),
+            );
+            *palette = quantize_palette(quantizer, &histogram, num_colors, kmeans);
+        }
+        refill_empty_palettes(&tiles, &mut palettes, num_colors, metric, quantizer, kmeans);
+
+        let mut changed = false;
+        for (colors, assignment) in tiles.iter().zip(assignments.iter_mut()) {
+            let best = find_best_palette(&palettes, colors, metric);
+            if best != *assignment {
+                *assignment = best;
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    TilePalettes {
+        palettes,
+        assignments,
+        columns,
+        rows,
+        tile_height,
+        reserve_transparent,
+    }
+}
+
+/// Reduces the colors of an image tile by tile, using the palette assigned to each tile. The
+/// pixels of the image are changed in place to the closest color available in the tile's palette.
+///
+/// # Arguments
+///
+/// - `image` - A mutable reference to the image that will be reduced in colors.
+/// - `tile_palettes` - The palettes and tile assignments returned by `find_tile_palettes`.
+/// - `metric` - The color distance metric.
+pub fn reduce_tile_colors(image: &mut Image, tile_palettes: &TilePalettes, metric: Metric) {
+    image.enumerate_pixels_mut().for_each(|(x, y, pixel)| {
+        let palette = &tile_palettes.palettes[tile_palettes.palette_at(x, y)];
+        let closest_color = find_closest_color(palette, &pixel.to_rgb(), metric);
+
+        *pixel = Rgba([
+            closest_color[0],
+            closest_color[1],
+            closest_color[2],
+            pixel[3],
+        ]);
+    });
+}
+
+/// Collects the colors of the pixels of the tile whose top-left corner is at `(left, top)`. Parts
+/// of the tile outside of the image are skipped, and so are pixels which aren't opaque unless
+/// `transparent` is set.
+pub fn get_tile_colors(
+    image: &Image,
+    (left, top): (u32, u32),
+    tile_height: u32,
+    transparent: bool,
+) -> Vec<Rgb<u8>> {
+    (0..tile_height)
+        .flat_map(|y| (0..TILE_SIZE).map(move |x| (left + x, top + y)))
+        .filter(|&(x, y)| x < image.width() && y < image.height())
+        .map(|(x, y)| image.get_pixel(x, y))
+        .filter(|pixel| transparent || pixel[3] == 255)
+        .map(|pixel| pixel.to_rgb())
+        .collect()
+}
+
+/// Groups tiles into `num_groups` groups with k-means on their average color, and returns the
+/// group of each tile. Tiles without any color are put into the first group.
+fn group_tiles(tiles: &[Vec<Rgb<u8>>], num_groups: usize, seed: u64) -> Vec<usize> {
+    let averages = tiles
+        .iter()
+        .filter(|colors| !colors.is_empty())
+        .map(|colors| {
+            let sum = colors
+                .iter()
+                .map(to_srgb)
+                .fold(Srgb::new(0.0, 0.0, 0.0), |a, b| a + b);
+            sum / colors.len() as f32
+        })
+        .collect::<Vec<_>>();
+    if averages.is_empty() {
+        return vec![0; tiles.len()];
+    }
+
+    let mut groups = get_kmeans(num_groups, 20, 0.0001, false, &averages, seed)
+        .indices
+        .into_iter();
+    tiles
+        .iter()
+        .map(|colors| match colors.is_empty() {
+            true => 0,
+            false => groups.next().map_or(0, usize::from),
+        })
+        .collect()
+}
+
+/// Replaces each empty palette with a palette of up to `num_colors` colors computed from the tile
+/// that is currently represented worst, so that all palettes are put to use.
+fn refill_empty_palettes(
+    tiles: &[Vec<Rgb<u8>>],
+    palettes: &mut [Vec<Rgb<u8>>],
+    num_colors: usize,
+    metric: Metric,
+    quantizer: &dyn Quantizer,
+    kmeans: &KmeansOptions,
+) {
+    while let Some(empty) = palettes.iter().position(|palette| palette.is_empty()) {
+        let worst = tiles
+            .iter()
+            .map(|colors| {
+                let best = find_best_palette(palettes, colors, metric);
+                (colors, compute_tile_error(&palettes[best], colors, metric))
+            })
+            .filter(|&(_, error)| error > 0.0)
+            .max_by(|(_, a), (_, b)| a.total_cmp(b));
+        let Some((colors, _)) = worst else {
+            return;
+        };
+        let histogram = color_histogram(colors.iter().copied());
+        palettes[empty] = quantize_palette(quantizer, &histogram, num_colors, kmeans);
+    }
+}
+
+/// Returns the index of the palette which reproduces the given tile colors with the smallest
+/// error. Empty palettes are never chosen unless all palettes are empty.
+pub fn find_best_palette(palettes: &[Vec<Rgb<u8>>], colors: &[Rgb<u8>], metric: Metric) -> usize {
+    palettes
+        .iter()
+        .enumerate()
+        .filter(|(_, palette)| !palette.is_empty())
+        .map(|(index, palette)| (index, compute_tile_error(palette, colors, metric)))
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map_or(0, |(index, _)| index)
+}
+
+/// Computes the sum of squared distances between each tile color and its closest palette color.
+pub fn compute_tile_error(palette: &[Rgb<u8>], colors: &[Rgb<u8>], metric: Metric) -> f64 {
+    let palette = palette
+        .iter()
+        .map(|color| metric.prepare(color))
+        .collect::<Vec<_>>();
+    colors
+        .iter()
+        .map(|color| {
+            let color = metric.prepare(color);
+            palette
+                .iter()
+                .map(|palette_color| metric.compute_prepared_distance(palette_color, &color))
+                .fold(f32::MAX, f32::min) as f64
+        })
+        .sum()
+}
+
+fn to_srgb(color: &Rgb<u8>) -> Srgb<f32> {
+    Srgb::new(color[0], color[1], color[2]).into_format()
+}
@@ -0,0 +1,492 @@
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+};
+
+use anyhow::{bail, Result};
+use image::{Pixel, Rgb, Rgba};
+
+use crate::{
+    args::Metric,
+    tiles::{TilePalettes, NUM_PALETTES, TILE_SIZE},
+    Image,
+};
+
+/// Number of tiles a BG map can address in each VRAM bank. A bank holds 384 tiles, but a tile map
+/// entry is a single byte, so only 256 of them are available to a background.
+pub const TILES_PER_BANK: usize = 256;
+
+/// Number of VRAM banks of the Game Boy Color.
+pub const NUM_BANKS: usize = 2;
+
+/// Largest useful merge threshold: tiles never differ in more pixels than a tile has, so any tiles
+/// can be merged with it.
+pub const MAX_MERGE_THRESHOLD: u32 = TILE_SIZE * TILE_SIZE;
+
+/// Number of the most similar tiles kept as merge candidates for each tile in each round of
+/// `merge_tiles`.
+const MERGE_CANDIDATES: usize = 8;
+
+/// Size of a tile in 2bpp format, in bytes.
+pub const TILE_BYTES: usize = 16;
+
+/// Bit of a BG map attribute which selects the VRAM bank of the tile.
+const ATTRIBUTE_BANK: u8 = 1 << 3;
+
+/// Bit of a BG map or OAM attribute which flips the tile horizontally.
+pub const ATTRIBUTE_FLIP_X: u8 = 1 << 5;
+
+/// Bit of a BG map or OAM attribute which flips the tile vertically.
+pub const ATTRIBUTE_FLIP_Y: u8 = 1 << 6;
+
+/// Flip bits of each orientation of a tile.
+const FLIPS: [u8; 4] = [
+    0,
+    ATTRIBUTE_FLIP_X,
+    ATTRIBUTE_FLIP_Y,
+    ATTRIBUTE_FLIP_X | ATTRIBUTE_FLIP_Y,
+];
+
+/// Color indices of the pixels of a tile, in row-major order.
+pub type TilePixels = [u8; (TILE_SIZE * TILE_SIZE) as usize];
+
+/// Low and high bits of the color indices of the pixels of a tile, one bit per pixel.
+type BitPlanes = [u64; 2];
+
+/// The data to be loaded into VRAM and palette memory to display an image as a background.
+#[derive(Debug, Clone)]
+pub struct TileData {
+    /// Unique tiles in 2bpp format. Tiles `0..TILES_PER_BANK` go to VRAM bank 0, and the rest to
+    /// bank 1.
+    pub tiles: Vec<[u8; TILE_BYTES]>,
+    /// Tile number within its VRAM bank for each tile of the image, in row-major order.
+    pub tile_map: Vec<u8>,
+    /// CGB BG map attributes for each tile of the image, in row-major order: palette number in
+    /// bits 0-2, VRAM bank in bit 3, horizontal flip in bit 5 and vertical flip in bit 6.
+    pub attribute_map: Vec<u8>,
+    /// `NUM_PALETTES` palettes of `COLORS_PER_PALETTE` colors, as BGR555 words.
+    pub palettes: Vec<u16>,
+    /// Number of tile columns.
+    pub columns: u32,
+    /// Number of tile rows.
+    pub rows: u32,
+    /// Number of unique tiles before near-identical tiles were merged.
+    pub unmerged_tiles: usize,
+}
+
+/// Builds the VRAM data of an image whose colors have been reduced with `reduce_tile_colors`, with
+/// 8x8 tiles.
+///
+/// # Arguments
+///
+/// - `image` - A mutable reference to the image reduced with the given tile palettes. If tiles are
+///   merged, they are redrawn so that the image matches the VRAM data.
+/// - `tile_palettes` - The palettes and tile assignments used to reduce the image.
+/// - `merge_threshold` - If set, near-identical tiles are merged when the image needs more unique
+///   tiles than the VRAM can hold, each tile being drawn with a tile which differs from it in at
+///   most this many pixels.
+///
+/// # Algorithm
+///
+/// Each pixel of a tile is converted to the index of its color in the tile's palette, and
+/// the indices are encoded in 2bpp format. If color 0 is reserved, transparent pixels use it and
+/// the other pixels use colors 1 to 3. Tiles which are identical, possibly after a horizontal
+/// and/or vertical flip, share one VRAM slot and use the flip bits of the attribute map, even when
+/// they use different palettes. Parts of a tile outside of the image use color 0.
+///
+/// If there are more unique tiles than `TILES_PER_BANK * NUM_BANKS`, similar tiles are merged into
+/// clusters by `merge_tiles`, the most similar first, until the tiles fit or no more clusters can
+/// be merged. All the tiles of a cluster are drawn with one of them, which differs from each of
+/// them in at most `merge_threshold` pixels, so the tiles always fit with `MAX_MERGE_THRESHOLD`.
+///
+/// # Returns
+///
+/// A `Result` which is `Ok` with the `TileData` on success, or `Err` with the number of unique
+/// tiles needed if they don't fit in the VRAM.
+pub fn build_tile_data(
+    image: &mut Image,
+    tile_palettes: &TilePalettes,
+    merge_threshold: Option<u32>,
+) -> Result<TileData> {
+    let TilePalettes {
+        palettes,
+        assignments,
+        columns,
+        rows,
+        reserve_transparent,
+        ..
+    } = tile_palettes;
+    let padded_palettes = tile_palettes.padded_palettes();
+    let budget = TILES_PER_BANK * NUM_BANKS;
+    let tile_origin = |tile: usize| {
+        (
+            tile as u32 % columns * TILE_SIZE,
+            tile as u32 / columns * TILE_SIZE,
+        )
+    };
+
+    // deduplicate tiles in their canonical orientation, i.e. the smallest of their flips
+    let mut unique_tiles = Vec::<TilePixels>::new();
+    let mut uses = Vec::new();
+    let mut indices = HashMap::new();
+    let mut occurrences = Vec::with_capacity(assignments.len());
+    for (tile, &assignment) in assignments.iter().enumerate() {
+        let (left, top) = tile_origin(tile);
+        let mut pixels = [0; (TILE_SIZE * TILE_SIZE) as usize];
+        for (i, index) in pixels.iter_mut().enumerate() {
+            let (x, y) = (i as u32 % TILE_SIZE, i as u32 / TILE_SIZE);
+            if let Some(pixel) = image.get_pixel_checked(left + x, top + y) {
+                let palette = &palettes[assignment];
+                *index = match (reserve_transparent, pixel[3]) {
+                    (true, 0) => 0,
+                    (true, _) => 1 + find_color_index(palette, &pixel.to_rgb()),
+                    (false, _) => find_color_index(palette, &pixel.to_rgb()),
+                };
+            }
+        }
+
+        let (canonical, flip) = FLIPS
+            .map(|flip| (flip_tile(&pixels, flip), flip))
+            .into_iter()
+            .min()
+            .unwrap_or((pixels, 0));
+        let index = *indices.entry(canonical).or_insert_with(|| {
+            unique_tiles.push(canonical);
+            uses.push(0);
+            unique_tiles.len() - 1
+        });
+        uses[index] += 1;
+        occurrences.push((index, flip));
+    }
+
+    let unmerged_tiles = unique_tiles.len();
+    if unmerged_tiles > budget {
+        if let Some(threshold) = merge_threshold {
+            let merged = merge_tiles(&unique_tiles, &uses, threshold, unmerged_tiles - budget);
+            for (index, flip) in occurrences.iter_mut() {
+                let (survivor, merge_flip) = merged[*index];
+                *index = survivor;
+                *flip ^= merge_flip;
+            }
+
+            // renumber the remaining tiles, and redraw the image with them
+            let mut numbers = HashMap::new();
+            let mut remaining = Vec::new();
+            for (index, _) in occurrences.iter_mut() {
+                *index = *numbers.entry(*index).or_insert_with(|| {
+                    remaining.push(unique_tiles[*index]);
+                    remaining.len() - 1
+                });
+            }
+            unique_tiles = remaining;
+            for (tile, &(index, flip)) in occurrences.iter().enumerate() {
+                draw_tile(
+                    image,
+                    tile_origin(tile),
+                    &flip_tile(&unique_tiles[index], flip),
+                    &padded_palettes[assignments[tile]],
+                    *reserve_transparent,
+                );
+            }
+        }
+    }
+
+    if unique_tiles.len() > budget {
+        let hint = match merge_threshold {
+            Some(threshold) => format!(
+                "try a merge threshold larger than {threshold}, up to {MAX_MERGE_THRESHOLD}, which \
+                 always fits"
+            ),
+            None => "try merging near-identical tiles with a merge threshold".to_string(),
+        };
+        bail!(
+            "the image needs {} unique tiles, but a background can use only {} ({} per bank); {}",
+            unique_tiles.len(),
+            budget,
+            TILES_PER_BANK,
+            hint
+        );
+    }
+
+    let tiles = unique_tiles.iter().map(encode_2bpp).collect();
+    let (tile_map, attribute_map) = occurrences
+        .iter()
+        .zip(assignments)
+        .map(|(&(index, flip), &assignment)| {
+            let bank = match index / TILES_PER_BANK {
+                0 => 0,
+                _ => ATTRIBUTE_BANK,
+            };
+            (
+                (index % TILES_PER_BANK) as u8,
+                (assignment % NUM_PALETTES) as u8 | bank | flip,
+            )
+        })
+        .unzip();
+
+    Ok(TileData {
+        tiles,
+        tile_map,
+        attribute_map,
+        palettes: tile_palettes.palette_memory(),
+        columns: *columns,
+        rows: *rows,
+        unmerged_tiles,
+    })
+}
+
+/// Merges tiles into clusters, each drawn with one of its tiles, until `count` tiles have been
+/// merged or no more clusters can be merged.
+///
+/// # Algorithm
+///
+/// Each tile starts as a cluster of its own, represented by itself. The merges run in rounds: each
+/// round finds the `MERGE_CANDIDATES` most similar representatives of each representative, up to
+/// `threshold` different pixels, and takes these pairs from the most similar to the least similar.
+/// Their clusters are merged if one of the two representatives is within `threshold` of every tile
+/// of both clusters. The representative which differs from the tiles in the fewest pixels,
+/// weighted by how often each tile is used, represents the merged cluster. Since clusters only
+/// grow, a pair which can't be merged never can later, so it is left out of the following rounds,
+/// which run until `count` tiles have been merged or a round finds no pair. With a threshold of
+/// `MAX_MERGE_THRESHOLD`, any clusters can be merged.
+///
+/// Keeping a bounded number of pairs per round keeps the memory linear in the number of tiles.
+///
+/// Returns, for each tile, the representative of its cluster and the flip to apply to it to
+/// approximate the tile.
+fn merge_tiles(
+    tiles: &[TilePixels],
+    uses: &[usize],
+    threshold: u32,
+    count: usize,
+) -> Vec<(usize, u8)> {
+    // each tile in each orientation, as bit planes, so that tiles are compared without flipping
+    // them again, 64 pixels at a time
+    let flipped = tiles
+        .iter()
+        .map(|tile| FLIPS.map(|flip| to_bit_planes(&flip_tile(tile, flip))))
+        .collect::<Vec<_>>();
+
+    let mut merged = (0..tiles.len()).map(|tile| (tile, 0)).collect::<Vec<_>>();
+    let mut members = (0..tiles.len()).map(|tile| vec![tile]).collect::<Vec<_>>();
+    let mut failed = HashSet::new();
+    let mut merged_count = 0;
+    while merged_count < count {
+        let representatives = (0..tiles.len())
+            .filter(|&tile| merged[tile].0 == tile)
+            .collect::<Vec<_>>();
+        let mut nearest = vec![Vec::<(u32, usize)>::new(); tiles.len()];
+        for (i, &a) in representatives.iter().enumerate() {
+            for &b in &representatives[i + 1..] {
+                let (distance, _) = compare_tiles(&flipped[a], &flipped[b][0]);
+                if distance <= threshold && !failed.contains(&(a, b)) {
+                    keep_nearest(&mut nearest[a], (distance, b));
+                    keep_nearest(&mut nearest[b], (distance, a));
+                }
+            }
+        }
+        let mut pairs = nearest
+            .iter()
+            .enumerate()
+            .flat_map(|(a, nearest)| {
+                nearest
+                    .iter()
+                    .map(move |&(distance, b)| (distance, a.min(b), a.max(b)))
+            })
+            .collect::<Vec<_>>();
+        pairs.sort_unstable();
+        pairs.dedup();
+        if pairs.is_empty() {
+            break;
+        }
+
+        for (_, a, b) in pairs {
+            if merged_count == count {
+                break;
+            }
+            // only pairs of representatives of different clusters
+            if merged[a].0 != a || merged[b].0 != b {
+                continue;
+            }
+            let candidates = [a, b].map(|representative| {
+                let matches = members[a]
+                    .iter()
+                    .chain(&members[b])
+                    .map(|&tile| {
+                        (
+                            tile,
+                            compare_tiles(&flipped[representative], &flipped[tile][0]),
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                let error = matches
+                    .iter()
+                    .map(|&(tile, (distance, _))| distance as usize * uses[tile])
+                    .sum::<usize>();
+                let fits = matches
+                    .iter()
+                    .all(|&(_, (distance, _))| distance <= threshold);
+                (representative, matches, error, fits)
+            });
+            let Some((representative, matches, _, _)) = candidates
+                .into_iter()
+                .filter(|(_, _, _, fits)| *fits)
+                .min_by_key(|&(representative, _, error, _)| {
+                    (error, Reverse(uses[representative]))
+                })
+            else {
+                failed.insert((a, b));
+                continue;
+            };
+
+            let absorbed = a + b - representative;
+            for (tile, (_, flip)) in matches {
+                merged[tile] = (representative, flip);
+            }
+            let absorbed_members = std::mem::take(&mut members[absorbed]);
+            members[representative].extend(absorbed_members);
+            merged_count += 1;
+        }
+    }
+    merged
+}
+
+/// Adds a `(distance, tile)` pair to a list of the `MERGE_CANDIDATES` closest tiles, sorted by
+/// distance and then tile, if it is closer than the farthest one.
+fn keep_nearest(nearest: &mut Vec<(u32, usize)>, pair: (u32, usize)) {
+    let position = nearest.partition_point(|&other| other < pair);
+    if position < MERGE_CANDIDATES {
+        nearest.insert(position, pair);
+        nearest.truncate(MERGE_CANDIDATES);
+    }
+}
+
+/// Converts a color to a BGR555 word, as stored in the palette memory of the Game Boy Color.
+///
+/// Red is stored in bits 0-4, green in bits 5-9 and blue in bits 10-14, each rounded to 5 bits.
+pub fn to_bgr555(color: &Rgb<u8>) -> u16 {
+    let [red, green, blue] = color.0.map(|channel| to_5bit(channel as f32) as u16);
+    red | green << 5 | blue << 10
+}
+
+/// Rounds a color with 8-bit channels, possibly fractional or out of range, to the closest color
+/// the Game Boy Color can display, i.e. to 5 bits per channel expanded back to 8 bits.
+pub fn to_15bit(channels: [f32; 3]) -> Rgb<u8> {
+    Rgb(channels.map(|channel| from_5bit(to_5bit(channel))))
+}
+
+/// Rounds an 8-bit channel value, possibly fractional or out of range, to the closest 5-bit value.
+pub fn to_5bit(channel: f32) -> u8 {
+    (channel.clamp(0.0, 255.0) * 31.0 / 255.0).round() as u8
+}
+
+/// Expands a 5-bit channel value to 8 bits by repeating its high bits in the low bits, so that 0
+/// and 31 become 0 and 255, and the 32 values are spread evenly.
+pub fn from_5bit(channel: u8) -> u8 {
+    channel << 3 | channel >> 2
+}
+
+/// Flips the pixels of a tile horizontally and/or vertically, according to the flip bits of a BG
+/// map attribute.
+fn flip_tile(pixels: &TilePixels, flip: u8) -> TilePixels {
+    let last = TILE_SIZE as usize - 1;
+    let mut flipped = [0; (TILE_SIZE * TILE_SIZE) as usize];
+    for (i, pixel) in flipped.iter_mut().enumerate() {
+        let (mut x, mut y) = (i % TILE_SIZE as usize, i / TILE_SIZE as usize);
+        if flip & ATTRIBUTE_FLIP_X != 0 {
+            x = last - x;
+        }
+        if flip & ATTRIBUTE_FLIP_Y != 0 {
+            y = last - y;
+        }
+        *pixel = pixels[y * TILE_SIZE as usize + x];
+    }
+    flipped
+}
+
+/// Compares two tiles in each orientation of the first one, given as bit planes in the order of
+/// `FLIPS`, and returns the smallest number of pixels whose color index differs, along with the
+/// flip of the first tile which gives it.
+fn compare_tiles(first: &[BitPlanes; 4], second: &BitPlanes) -> (u32, u8) {
+    first
+        .iter()
+        .zip(FLIPS)
+        .map(|(first, flip)| (count_different_pixels(first, second), flip))
+        .min()
+        .unwrap_or((u32::MAX, 0))
+}
+
+/// Returns the number of pixels whose color index differs between two tiles.
+fn count_different_pixels(first: &BitPlanes, second: &BitPlanes) -> u32 {
+    ((first[0] ^ second[0]) | (first[1] ^ second[1])).count_ones()
+}
+
+/// Splits the color indices of a tile into the bit planes of their low and high bits, one bit per
+/// pixel in row-major order.
+fn to_bit_planes(pixels: &TilePixels) -> BitPlanes {
+    pixels
+        .iter()
+        .enumerate()
+        .fold([0, 0], |[low, high], (i, &index)| {
+            [
+                low | (index as u64 & 1) << i,
+                high | (index as u64 >> 1 & 1) << i,
+            ]
+        })
+}
+
+/// Draws the pixels of a tile into the image, with their colors in the given palette, as laid out
+/// by `TilePalettes::padded_palettes`. Colors missing from the palette are drawn black. If color 0
+/// is reserved, it is drawn transparent and the other colors opaque.
+fn draw_tile(
+    image: &mut Image,
+    (left, top): (u32, u32),
+    pixels: &TilePixels,
+    palette: &[Rgb<u8>],
+    reserve_transparent: bool,
+) {
+    for (i, &index) in pixels.iter().enumerate() {
+        let color = palette
+            .get(index as usize)
+            .copied()
+            .unwrap_or(Rgb([0, 0, 0]));
+        let (x, y) = (left + i as u32 % TILE_SIZE, top + i as u32 / TILE_SIZE);
+        if let Some(pixel) = image.get_pixel_mut_checked(x, y) {
+            let alpha = match (reserve_transparent, index) {
+                (true, 0) => 0,
+                (true, _) => 255,
+                (false, _) => pixel[3],
+            };
+            *pixel = Rgba([color[0], color[1], color[2], alpha]);
+        }
+    }
+}
+
+/// Encodes the color indices of an 8x8 tile, in row-major order, in 2bpp format: two bytes per
+/// row, the first holding the low bit of each index and the second the high bit, with the
+/// leftmost pixel in the most significant bit.
+pub fn encode_2bpp(color_indices: &TilePixels) -> [u8; TILE_BYTES] {
+    let mut data = [0; TILE_BYTES];
+    for (row, indices) in color_indices.chunks(TILE_SIZE as usize).enumerate() {
+        for (column, &index) in indices.iter().enumerate() {
+            let bit = 7 - column;
+            data[row * 2] |= (index & 1) << bit;
+            data[row * 2 + 1] |= (index >> 1 & 1) << bit;
+        }
+    }
+    data
+}
+
+/// Returns the index of the palette color closest to the given color. The image has already been
+/// reduced to the palette, so the plain RGB distance is enough to find the exact color.
+pub fn find_color_index(palette: &[Rgb<u8>], color: &Rgb<u8>) -> u8 {
+    palette
+        .iter()
+        .enumerate()
+        .min_by(|(_, a), (_, b)| {
+            Metric::Rgb
+                .compute_squared_distance(a, color)
+                .total_cmp(&Metric::Rgb.compute_squared_distance(b, color))
+        })
+        .map_or(0, |(index, _)| index as u8)
+}